@@ -0,0 +1,429 @@
+//! Animated GIF and WebP output.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use color_quant::NeuQuant;
+use ffmpeg_next::format::Pixel;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::imageops;
+use image::{ColorType, DynamicImage, ImageEncoder, ImageFormat, RgbImage};
+
+use crate::encoder::slot_at;
+use crate::video::{Encoding, VideoEncoder};
+use crate::Error;
+
+/// Frame rate cap when none is set.
+const DEFAULT_MAX_FPS: u32 = 15;
+
+/// GIF delays are in hundredths of a second and browsers slow down shorter ones,
+/// so GIFs can't go faster than 50 frames per second.
+const MIN_GIF_DELAY: u64 = 2;
+
+/// How many pixels of the video the palette is built from, evenly spread over all frames.
+const PALETTE_SAMPLES: usize = 1 << 20;
+
+/// Trade-off between the speed and the quality of the palette, from 1 (best) to 30.
+const PALETTE_SAMPLING: i32 = 10;
+
+/// How colors missing from the GIF palette are rendered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+	/// Closest palette color, flat areas stay flat but gradients show bands.
+	None,
+	/// Floyd-Steinberg error diffusion, smooth gradients at the cost of some noise.
+	#[default]
+	FloydSteinberg,
+}
+
+/// How GIF and WebP animations are written.
+#[derive(Debug, Clone, Default)]
+pub struct AnimationOptions {
+	/// Frames closer than `1 / max_fps` replace each other, 15 when not set.
+	pub max_fps: Option<u32>,
+	/// GIF only.
+	pub dither: Dither,
+	/// Size of the GIF palette, from 2 to 256 (the default).
+	pub colors: Option<u16>,
+}
+
+/// Writes frames as an animated image, skipping frames identical to the previous one.
+pub(crate) struct AnimationEncoder {
+	writer: Writer,
+	max_fps: u32,
+	/// The latest frame and its position, replaced by newer frames until the next slot.
+	pending: Option<(RgbImage, Duration)>,
+	/// Frames dropped because they were identical or too close to the previous one.
+	skipped: u64,
+}
+
+enum Writer {
+	/// GIF frames are kept until the end, the palette is built from all of them.
+	Gif {
+		path: PathBuf,
+		options: AnimationOptions,
+		frames: Vec<(CompressedFrame, Duration)>,
+	},
+	Webp(VideoEncoder),
+}
+
+impl AnimationEncoder {
+	pub(crate) fn gif(path: &Path, options: &AnimationOptions) -> AnimationEncoder {
+		tracing::info!("encoding gif");
+
+		AnimationEncoder {
+			max_fps: options
+				.max_fps
+				.unwrap_or(DEFAULT_MAX_FPS)
+				.clamp(1, (100 / MIN_GIF_DELAY) as u32),
+			writer: Writer::Gif {
+				path: path.to_path_buf(),
+				options: options.clone(),
+				frames: Vec::new(),
+			},
+			pending: None,
+			skipped: 0,
+		}
+	}
+
+	pub(crate) fn webp(
+		path: &Path,
+		options: &AnimationOptions,
+		width: u32,
+		height: u32,
+	) -> Result<AnimationEncoder, Error> {
+		let encoding = Encoding {
+			encoders: vec![(
+				"libwebp_anim",
+				vec![("quality".to_owned(), "75".to_owned())],
+			)],
+			pixel_format: Pixel::YUV420P,
+			gop: None,
+			// loop forever
+			muxer_options: vec![("loop".to_owned(), "0".to_owned())],
+		};
+
+		Ok(AnimationEncoder {
+			max_fps: options.max_fps.unwrap_or(DEFAULT_MAX_FPS).max(1),
+			writer: Writer::Webp(VideoEncoder::new(path, &encoding, width, height, None)?),
+			pending: None,
+			skipped: 0,
+		})
+	}
+
+	pub(crate) fn encode(&mut self, image: &RgbImage, position: Duration) -> Result<(), Error> {
+		if let Some((pending, at)) = &mut self.pending {
+			if pending == image {
+				self.skipped += 1;
+				return Ok(());
+			}
+
+			if slot_at(*at, self.max_fps) == slot_at(position, self.max_fps) {
+				*pending = image.clone();
+				self.skipped += 1;
+				return Ok(());
+			}
+		}
+
+		if let Some((pending, at)) = self.pending.replace((image.clone(), position)) {
+			self.write(pending, at)?;
+		}
+
+		Ok(())
+	}
+
+	/// Writes the pending frame and finalizes the file, the last frame is shown until `end`.
+	pub(crate) fn finish(mut self, end: Duration) -> Result<(), Error> {
+		if let Some((pending, at)) = self.pending.take() {
+			self.write(pending, at)?;
+		}
+
+		tracing::info!("{} duplicate or too frequent frames skipped", self.skipped);
+
+		match self.writer {
+			Writer::Gif {
+				path,
+				options,
+				frames,
+			} => write_gif(&path, &options, &frames, end),
+			Writer::Webp(mut encoder) => encoder.finish(),
+		}
+	}
+
+	fn write(&mut self, image: RgbImage, position: Duration) -> Result<(), Error> {
+		match &mut self.writer {
+			Writer::Gif { frames, .. } => {
+				frames.push((CompressedFrame::new(&image)?, position));
+				Ok(())
+			}
+			Writer::Webp(encoder) => encoder.encode(&image, position),
+		}
+	}
+}
+
+/// Writes `frames` with one palette for the whole animation.
+fn write_gif(
+	path: &Path,
+	options: &AnimationOptions,
+	frames: &[(CompressedFrame, Duration)],
+	end: Duration,
+) -> Result<(), Error> {
+	let Some((first, _)) = frames.first() else {
+		return Ok(());
+	};
+
+	let (width, height) = (first.width, first.height);
+	let palette = palette(frames, options.colors.unwrap_or(256))?;
+
+	let too_large = |_| format!("{}x{} is too large for a gif", width, height);
+	let (gif_width, gif_height) = (
+		u16::try_from(width).map_err(too_large)?,
+		u16::try_from(height).map_err(too_large)?,
+	);
+
+	let file = BufWriter::new(File::create(path)?);
+	let mut encoder = gif::Encoder::new(file, gif_width, gif_height, &palette.color_map_rgb())?;
+	encoder.set_repeat(gif::Repeat::Infinite)?;
+
+	let positions: Vec<_> = frames.iter().map(|(_, at)| *at).collect();
+	let delays = gif_delays(&positions, end);
+
+	let mut previous: Option<Vec<u8>> = None;
+
+	for ((frame, _), delay) in frames.iter().zip(delays) {
+		let indices = index_colors(&frame.image()?, &palette, options.dither);
+
+		let mut frame = changed_region(previous.as_deref(), &indices, width, height);
+		frame.delay = u16::try_from(delay).unwrap_or(u16::MAX);
+		encoder.write_frame(&frame)?;
+
+		previous = Some(indices);
+	}
+
+	Ok(())
+}
+
+/// Delays in hundredths of a second of frames shown from `positions` on, the last one until `end`.
+fn gif_delays(positions: &[Duration], end: Duration) -> Vec<u64> {
+	// hundredths of a second shown so far, delays are rounded without drifting
+	let mut shown = 0;
+
+	(0..positions.len())
+		.map(|index| {
+			let until = positions.get(index + 1).copied().unwrap_or(end);
+			let delay = (until.as_millis() as u64 / 10)
+				.saturating_sub(shown)
+				.max(MIN_GIF_DELAY);
+			shown += delay;
+			delay
+		})
+		.collect()
+}
+
+/// Builds a palette of `colors` colors from pixels sampled from all frames.
+fn palette(frames: &[(CompressedFrame, Duration)], colors: u16) -> Result<NeuQuant, Error> {
+	let pixels: usize = frames
+		.iter()
+		.map(|(frame, _)| frame.width as usize * frame.height as usize)
+		.sum();
+	let step = (pixels / PALETTE_SAMPLES).max(1);
+
+	let mut samples = Vec::with_capacity((pixels / step + 1) * 4);
+	// pixels of the previous frames, every `step`th pixel of the whole video is sampled
+	let mut seen = 0;
+
+	for (frame, _) in frames {
+		let image = frame.image()?;
+
+		let first = (step - seen % step) % step;
+		for pixel in image.pixels().skip(first).step_by(step) {
+			samples.extend_from_slice(&[pixel[0], pixel[1], pixel[2], u8::MAX]);
+		}
+
+		seen += image.width() as usize * image.height() as usize;
+	}
+
+	Ok(NeuQuant::new(
+		PALETTE_SAMPLING,
+		usize::from(colors.clamp(2, 256)),
+		&samples,
+	))
+}
+
+/// A frame kept as a PNG until the GIF is written, screen content compresses well.
+struct CompressedFrame {
+	png: Vec<u8>,
+	width: u32,
+	height: u32,
+}
+
+impl CompressedFrame {
+	fn new(image: &RgbImage) -> Result<CompressedFrame, Error> {
+		let mut png = Vec::new();
+		PngEncoder::new_with_quality(&mut png, CompressionType::Fast, FilterType::Sub)
+			.write_image(
+				image.as_raw(),
+				image.width(),
+				image.height(),
+				ColorType::Rgb8,
+			)?;
+
+		Ok(CompressedFrame {
+			png,
+			width: image.width(),
+			height: image.height(),
+		})
+	}
+
+	fn image(&self) -> Result<RgbImage, Error> {
+		Ok(image::load_from_memory_with_format(&self.png, ImageFormat::Png)?.into_rgb8())
+	}
+}
+
+/// Maps every pixel of `image` to the index of a palette color.
+fn index_colors(image: &RgbImage, palette: &NeuQuant, dither: Dither) -> Vec<u8> {
+	let mut image = DynamicImage::ImageRgb8(image.clone()).into_rgba8();
+
+	if dither == Dither::FloydSteinberg {
+		imageops::dither(&mut image, palette);
+	}
+
+	imageops::index_colors(&image, palette).into_raw()
+}
+
+/// A frame with the smallest rectangle of `indices` that differs from `previous`,
+/// the rest of the previous frame stays on screen.
+fn changed_region<'a>(
+	previous: Option<&[u8]>,
+	indices: &[u8],
+	width: u32,
+	height: u32,
+) -> gif::Frame<'a> {
+	let (width, height) = (width as usize, height as usize);
+
+	let (mut left, mut top, mut right, mut bottom) = (0, 0, width, height);
+
+	if let Some(previous) = previous {
+		let changed = |x: usize, y: usize| previous[y * width + x] != indices[y * width + x];
+
+		match (0..height).find(|y| (0..width).any(|x| changed(x, *y))) {
+			Some(first) => {
+				top = first;
+				bottom = (top..height)
+					.rev()
+					.find(|y| (0..width).any(|x| changed(x, *y)))
+					.map_or(height, |last| last + 1);
+				left = (0..width)
+					.find(|x| (top..bottom).any(|y| changed(*x, y)))
+					.unwrap_or(0);
+				right = (left..width)
+					.rev()
+					.find(|x| (top..bottom).any(|y| changed(*x, y)))
+					.map_or(width, |last| last + 1);
+			}
+			// nothing changed, a single pixel keeps the frame and its delay
+			None => (right, bottom) = (1, 1),
+		}
+	}
+
+	let mut buffer = Vec::with_capacity((right - left) * (bottom - top));
+	for y in top..bottom {
+		buffer.extend_from_slice(&indices[y * width + left..y * width + right]);
+	}
+
+	gif::Frame {
+		left: left as u16,
+		top: top as u16,
+		width: (right - left) as u16,
+		height: (bottom - top) as u16,
+		dispose: gif::DisposalMethod::Keep,
+		buffer: Cow::Owned(buffer),
+		..gif::Frame::default()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn region(frame: &gif::Frame) -> (u16, u16, u16, u16) {
+		(frame.left, frame.top, frame.width, frame.height)
+	}
+
+	#[test]
+	fn first_frame_is_whole() {
+		let indices = vec![0; 12];
+		let frame = changed_region(None, &indices, 4, 3);
+
+		assert_eq!(region(&frame), (0, 0, 4, 3));
+		assert_eq!(frame.buffer.len(), 12);
+	}
+
+	#[test]
+	fn unchanged_frame() {
+		let indices = vec![7; 12];
+		let frame = changed_region(Some(&indices), &indices, 4, 3);
+
+		assert_eq!(region(&frame), (0, 0, 1, 1));
+		assert_eq!(frame.buffer.as_ref(), &[7]);
+	}
+
+	#[test]
+	fn single_changed_pixel() {
+		let previous = vec![0; 12];
+		let mut indices = previous.clone();
+		// x 2, y 1
+		indices[6] = 5;
+
+		let frame = changed_region(Some(&previous), &indices, 4, 3);
+
+		assert_eq!(region(&frame), (2, 1, 1, 1));
+		assert_eq!(frame.buffer.as_ref(), &[5]);
+	}
+
+	#[test]
+	fn changed_rectangle() {
+		let previous = vec![0; 20];
+		let mut indices = previous.clone();
+		// x 1, y 1 and x 3, y 2 of a 5x4 frame
+		indices[6] = 1;
+		indices[13] = 2;
+
+		let frame = changed_region(Some(&previous), &indices, 5, 4);
+
+		assert_eq!(region(&frame), (1, 1, 3, 2));
+		assert_eq!(frame.buffer.as_ref(), &[1, 0, 0, 0, 0, 2]);
+	}
+
+	#[test]
+	fn delays_add_up_to_the_end() {
+		// 30 fps, a frame every 33.3ms does not fit hundredths of a second
+		let positions: Vec<_> = (0..90)
+			.map(|index| Duration::from_secs(index) / 30)
+			.collect();
+
+		let delays = gif_delays(&positions, Duration::from_secs(3));
+
+		assert_eq!(delays.len(), 90);
+		assert_eq!(delays.iter().sum::<u64>(), 300);
+		assert!(delays.iter().all(|delay| (3..=4).contains(delay)));
+	}
+
+	#[test]
+	fn delays_have_a_minimum() {
+		let positions = [
+			Duration::ZERO,
+			Duration::from_millis(5),
+			Duration::from_millis(10),
+			Duration::from_millis(500),
+		];
+
+		let delays = gif_delays(&positions, Duration::from_secs(1));
+
+		assert_eq!(delays, [2, 2, 46, 50]);
+	}
+}
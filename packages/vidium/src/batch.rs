@@ -0,0 +1,625 @@
+//! Several recordings described in a TOML manifest, run as parallel pages of one browser.
+//!
+//! ```toml
+//! [[job]]
+//! name = "home"
+//! url = "https://example.com"
+//! output = "videos/home.mp4"
+//! duration = 5
+//!
+//! [[job]]
+//! url = "https://example.com/pricing"
+//! width = 1280
+//! height = 720
+//! fps = 30
+//! scenario = "pricing.yaml"
+//! isolated = true
+//! ```
+//!
+//! Relative paths are resolved against the directory of the manifest.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chromiumoxide::cdp::browser_protocol::browser::BrowserContextId;
+use chromiumoxide::cdp::browser_protocol::emulation::SetDeviceMetricsOverrideParams;
+use chromiumoxide::cdp::browser_protocol::target::{
+	CreateBrowserContextParams, CreateTargetParams,
+};
+use chromiumoxide::{Browser, Page};
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::sync::watch;
+use url::Url;
+
+use crate::animation::Dither;
+use crate::capture::CaptureFormat;
+use crate::output::Format;
+use crate::pipeline::BacklogPolicy;
+use crate::recorder::{scaled_size, Recorder};
+use crate::recording::{record_page, RecordingOptions, RecordingSummary};
+use crate::scenario::Scenario;
+use crate::video::{Codec, PixelFormat, Tune};
+use crate::{Error, VidiumError};
+
+/// Recordings to run in one browser, loaded from a TOML manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Batch {
+	#[serde(default, rename = "job")]
+	pub jobs: Vec<Job>,
+	/// Directory of the manifest, relative paths of the jobs are resolved against it.
+	#[serde(skip)]
+	pub base: PathBuf,
+}
+
+/// One recording of a [`Batch`].
+///
+/// Options that are not set are taken from the [`Recorder`] running the batch.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+	/// Shown in the summary, the url when not set.
+	pub name: Option<String>,
+	pub url: String,
+	pub output: Option<PathBuf>,
+	/// Width of the page.
+	pub width: Option<u32>,
+	/// Height of the page.
+	pub height: Option<u32>,
+	pub device_scale_factor: Option<f64>,
+	/// Open the page in its own browser context, with separate cookies and storage.
+	#[serde(default)]
+	pub isolated: bool,
+	/// Start capturing on a blank page, so that the page load is recorded too.
+	#[serde(default)]
+	pub record_from_start: bool,
+	pub capture_format: Option<CaptureFormat>,
+	pub jpeg_quality: Option<u8>,
+	pub max_capture_width: Option<u32>,
+	pub max_capture_height: Option<u32>,
+	pub every_nth_frame: Option<u32>,
+	pub format: Option<Format>,
+	pub codec: Option<Codec>,
+	pub crf: Option<u8>,
+	pub preset: Option<String>,
+	/// Bits per second.
+	pub bitrate: Option<u64>,
+	/// Bits per second.
+	pub max_bitrate: Option<u64>,
+	pub gop: Option<u32>,
+	pub tune: Option<Tune>,
+	pub pixel_format: Option<PixelFormat>,
+	pub lossless: Option<bool>,
+	/// GIF and WebP only.
+	pub max_fps: Option<u32>,
+	/// GIF only.
+	pub dither: Option<Dither>,
+	/// GIF only.
+	pub colors: Option<u16>,
+	/// Seconds.
+	pub duration: Option<f64>,
+	pub max_frames: Option<u64>,
+	/// Seconds.
+	pub stop_after_idle: Option<f64>,
+	pub fps: Option<u32>,
+	#[serde(default)]
+	pub deterministic: bool,
+	pub on_backlog: Option<BacklogPolicy>,
+	pub max_queued_frames: Option<usize>,
+	/// Path to the control script.
+	pub script: Option<PathBuf>,
+	/// Path to the scenario.
+	pub scenario: Option<PathBuf>,
+	pub wait_for_selector: Option<String>,
+	/// Milliseconds.
+	pub wait_for_network_idle: Option<u64>,
+	pub wait_for_function: Option<String>,
+	/// Milliseconds.
+	pub delay: Option<u64>,
+	/// Seconds.
+	pub wait_timeout: Option<f64>,
+}
+
+/// How a job of the batch went.
+#[derive(Debug)]
+pub struct JobReport {
+	pub name: String,
+	/// How long the job took, including opening the page.
+	pub elapsed: Duration,
+	pub result: Result<RecordingSummary, VidiumError>,
+}
+
+impl Batch {
+	pub fn parse(source: &str) -> Result<Self, Error> {
+		let batch: Batch = toml::from_str(source)?;
+		batch.check()?;
+		Ok(batch)
+	}
+
+	/// Rejects jobs that would overwrite each other or can't be recorded.
+	fn check(&self) -> Result<(), Error> {
+		let mut outputs = HashSet::new();
+
+		for (index, job) in self.jobs.iter().enumerate() {
+			let name = job.name.as_deref().unwrap_or(&job.url);
+
+			if let Some(output) = &job.output {
+				if !outputs.insert(output) {
+					return Err(format!(
+						"job {} ({}): {} is the output of another job",
+						index + 1,
+						name,
+						output.display()
+					)
+					.into());
+				}
+			}
+
+			let waits = job.wait_for_selector.is_some()
+				|| job.wait_for_function.is_some()
+				|| job.wait_for_network_idle.is_some()
+				|| job.delay.is_some();
+
+			if job.record_from_start && waits {
+				return Err(format!(
+					"job {} ({}): record_from_start can't be combined with wait_for_* or delay",
+					index + 1,
+					name
+				)
+				.into());
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+		let path = path.as_ref();
+		let mut batch = Self::parse(&std::fs::read_to_string(path)?)?;
+		batch.base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+		Ok(batch)
+	}
+
+	/// Runs the jobs in the browser of the `recorder`, at most `concurrency` at a time.
+	///
+	/// Reports are returned in the order of the jobs. A failed job doesn't stop the others.
+	pub async fn run(
+		&self,
+		recorder: &Recorder,
+		concurrency: usize,
+	) -> Result<Vec<JobReport>, VidiumError> {
+		let (reports, _) = self
+			.run_or(recorder, concurrency, futures::future::pending::<()>())
+			.await?;
+		Ok(reports)
+	}
+
+	/// Like [`Batch::run`], but once `signal` resolves the running jobs are stopped
+	/// and the rest are skipped.
+	///
+	/// Also returns the output of `signal` if the batch was stopped by it.
+	pub async fn run_or<T>(
+		&self,
+		recorder: &Recorder,
+		concurrency: usize,
+		signal: impl Future<Output = T>,
+	) -> Result<(Vec<JobReport>, Option<T>), VidiumError> {
+		let session = recorder.browser().await?;
+		let browser = session.browser();
+
+		let (stop, stopped) = watch::channel(false);
+
+		let jobs = futures::stream::iter(self.jobs.iter().enumerate())
+			.map(|(index, job)| {
+				let stopped = stopped.clone();
+
+				async move {
+					let name = job.name.clone().unwrap_or_else(|| job.url.clone());
+					let started = Instant::now();
+
+					let result = if *stopped.borrow() {
+						Err(VidiumError::Capture(
+							"interrupted before the job started".into(),
+						))
+					} else {
+						tracing::info!("job {}: {} started", index + 1, name);
+						self.run_job(browser, recorder, job, stopped).await
+					};
+
+					match &result {
+						Ok(_) => tracing::info!("job {}: {} finished", index + 1, name),
+						Err(e) => tracing::error!("job {}: {} failed: {}", index + 1, name, e),
+					}
+
+					let report = JobReport {
+						name,
+						elapsed: started.elapsed(),
+						result,
+					};
+
+					(index, report)
+				}
+			})
+			.buffer_unordered(concurrency.max(1))
+			.collect::<Vec<_>>();
+
+		tokio::pin!(jobs, signal);
+
+		let (mut reports, signal) = tokio::select! {
+			reports = &mut jobs => (reports, None),
+			value = &mut signal => {
+				tracing::info!("stopping the running jobs");
+				let _ = stop.send(true);
+				(jobs.await, Some(value))
+			}
+		};
+
+		session.close().await?;
+
+		reports.sort_by_key(|(index, _)| *index);
+		let reports = reports.into_iter().map(|(_, report)| report).collect();
+
+		Ok((reports, signal))
+	}
+
+	async fn run_job(
+		&self,
+		browser: &Browser,
+		recorder: &Recorder,
+		job: &Job,
+		stopped: watch::Receiver<bool>,
+	) -> Result<RecordingSummary, VidiumError> {
+		let url: Url = job.url.parse().map_err(|e| {
+			VidiumError::InvalidArgument(format!("invalid url {:?}: {}", job.url, e).into())
+		})?;
+
+		let options = self.recording_options(recorder, job, &url)?;
+
+		if options.duration.is_none()
+			&& options.max_frames.is_none()
+			&& options.stop_after_idle.is_none()
+			&& options.scenario.is_none()
+			&& options.script.is_none()
+		{
+			return Err(VidiumError::InvalidArgument(
+				"a job needs a duration, max_frames, stop_after_idle, a script or a scenario to stop"
+					.into(),
+			));
+		}
+
+		let context = if job.isolated {
+			Some(
+				browser
+					.create_browser_context(CreateBrowserContextParams::default())
+					.await
+					.map_err(|e| VidiumError::Browser(e.into()))?,
+			)
+		} else {
+			None
+		};
+
+		let result = async {
+			let page = self
+				.open(browser, recorder, job, &url, context.clone())
+				.await
+				.map_err(VidiumError::Navigation)?;
+
+			let result = match record_page(&page, &options).await {
+				Ok(recording) => recording
+					.wait_or(wait_stopped(stopped))
+					.await
+					.map(|(summary, _)| summary),
+				Err(e) => Err(e),
+			};
+
+			let _ = page.close().await;
+			result
+		}
+		.await;
+
+		if let Some(context) = context {
+			let _ = browser.dispose_browser_context(context).await;
+		}
+
+		result
+	}
+
+	async fn open(
+		&self,
+		browser: &Browser,
+		recorder: &Recorder,
+		job: &Job,
+		url: &Url,
+		context: Option<BrowserContextId>,
+	) -> Result<Page, Error> {
+		let defaults = recorder.options();
+
+		let open = if job.record_from_start {
+			"about:blank"
+		} else {
+			url.as_str()
+		};
+
+		let mut target = CreateTargetParams::new(open);
+		target.browser_context_id = context;
+
+		let page = browser.new_page(target).await?;
+
+		page.execute(SetDeviceMetricsOverrideParams::new(
+			job.width.unwrap_or(defaults.width),
+			job.height.unwrap_or(defaults.height),
+			// 0 keeps the ratio of the screen
+			job.device_scale_factor
+				.or(defaults.device_scale_factor)
+				.unwrap_or(0.0),
+			false,
+		))
+		.await?;
+
+		page.wait_for_navigation().await?;
+
+		Ok(page)
+	}
+
+	fn recording_options(
+		&self,
+		recorder: &Recorder,
+		job: &Job,
+		url: &Url,
+	) -> Result<RecordingOptions, VidiumError> {
+		let defaults = recorder.options();
+		let mut options = defaults.recording.clone();
+
+		let seconds = |value: f64| {
+			Duration::try_from_secs_f64(value).map_err(|e| {
+				VidiumError::InvalidArgument(format!("invalid duration {}: {}", value, e).into())
+			})
+		};
+
+		options.output = job.output.as_ref().map(|output| self.base.join(output));
+
+		// jobs rendering the template to the same name must not write the same file
+		if options.output.is_none() {
+			options.no_clobber = true;
+		}
+
+		if let Some(scale) = job.device_scale_factor.or(defaults.device_scale_factor) {
+			options.size = Some(scaled_size(
+				job.width.unwrap_or(defaults.width),
+				job.height.unwrap_or(defaults.height),
+				scale,
+			)?);
+		}
+
+		if let Some(format) = job.capture_format {
+			options.capture.format = format;
+		}
+
+		options.capture.jpeg_quality = job.jpeg_quality.or(options.capture.jpeg_quality);
+		options.capture.max_width = job.max_capture_width.or(options.capture.max_width);
+		options.capture.max_height = job.max_capture_height.or(options.capture.max_height);
+		options.capture.every_nth_frame = job.every_nth_frame.or(options.capture.every_nth_frame);
+
+		options.format = job.format.or(options.format);
+		options.video.codec = job.codec.or(options.video.codec);
+		options.video.crf = job.crf.or(options.video.crf);
+		options.video.bitrate = job.bitrate.or(options.video.bitrate);
+		options.video.max_bitrate = job.max_bitrate.or(options.video.max_bitrate);
+		options.video.gop = job.gop.or(options.video.gop);
+		options.video.tune = job.tune.or(options.video.tune);
+		options.video.pixel_format = job.pixel_format.unwrap_or(options.video.pixel_format);
+		options.video.lossless = job.lossless.unwrap_or(options.video.lossless);
+
+		if let Some(preset) = &job.preset {
+			options.video.preset = Some(preset.clone());
+		}
+
+		options.animation.max_fps = job.max_fps.or(options.animation.max_fps);
+		options.animation.dither = job.dither.unwrap_or(options.animation.dither);
+		options.animation.colors = job.colors.or(options.animation.colors);
+
+		if let Some(duration) = job.duration {
+			options.duration = Some(seconds(duration)?);
+		}
+
+		if let Some(idle) = job.stop_after_idle {
+			options.stop_after_idle = Some(seconds(idle)?);
+		}
+
+		options.max_frames = job.max_frames.or(options.max_frames);
+		options.fps = job.fps.or(options.fps);
+		options.deterministic |= job.deterministic;
+		options.on_backlog = job.on_backlog.unwrap_or(options.on_backlog);
+		options.max_queued_frames = job.max_queued_frames.or(options.max_queued_frames);
+
+		if let Some(script) = &job.script {
+			let path = self.base.join(script);
+			options.script = Some(std::fs::read_to_string(&path).map_err(|e| {
+				VidiumError::InvalidArgument(format!("can't read {}: {}", path.display(), e).into())
+			})?);
+		}
+
+		if let Some(scenario) = &job.scenario {
+			let path = self.base.join(scenario);
+			options.scenario = Some(Scenario::load(&path).map_err(|e| {
+				VidiumError::InvalidArgument(format!("can't load {}: {}", path.display(), e).into())
+			})?);
+		}
+
+		if let Some(selector) = &job.wait_for_selector {
+			options.wait.selector = Some(selector.clone());
+		}
+
+		if let Some(function) = &job.wait_for_function {
+			options.wait.function = Some(function.clone());
+		}
+
+		if let Some(idle) = job.wait_for_network_idle {
+			options.wait.network_idle = Some(Duration::from_millis(idle));
+		}
+
+		if let Some(delay) = job.delay {
+			options.wait.delay = Some(Duration::from_millis(delay));
+		}
+
+		if let Some(timeout) = job.wait_timeout {
+			options.wait.timeout = Some(seconds(timeout)?);
+		}
+
+		if job.record_from_start {
+			options.navigate_to = Some(url.clone());
+		}
+
+		Ok(options)
+	}
+}
+
+/// Resolves once the batch is stopped.
+async fn wait_stopped(mut stopped: watch::Receiver<bool>) {
+	while !*stopped.borrow_and_update() {
+		if stopped.changed().await.is_err() {
+			// the batch is finished, it can't be stopped anymore
+			return futures::future::pending().await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::RecorderOptions;
+
+	#[test]
+	fn parses_jobs() {
+		let batch = Batch::parse(
+			r#"
+			[[job]]
+			url = "https://example.com"
+			duration = 5
+
+			[[job]]
+			name = "pricing"
+			url = "https://example.com/pricing"
+			output = "pricing.gif"
+			on_backlog = "drop-oldest"
+			dither = "none"
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(batch.jobs.len(), 2);
+		assert_eq!(batch.jobs[0].duration, Some(5.0));
+		assert_eq!(batch.jobs[1].name.as_deref(), Some("pricing"));
+		assert_eq!(batch.jobs[1].on_backlog, Some(BacklogPolicy::DropOldest));
+		assert_eq!(batch.jobs[1].dither, Some(Dither::None));
+	}
+
+	#[test]
+	fn rejects_unknown_options() {
+		assert!(Batch::parse("[[job]]\nurl = \"https://example.com\"\ncrf_value = 20\n").is_err());
+	}
+
+	#[test]
+	fn rejects_duplicate_outputs() {
+		let err = Batch::parse(
+			r#"
+			[[job]]
+			url = "https://example.com"
+			output = "video.mp4"
+
+			[[job]]
+			name = "second"
+			url = "https://example.com/pricing"
+			output = "video.mp4"
+			"#,
+		)
+		.unwrap_err();
+
+		assert_eq!(
+			err.to_string(),
+			"job 2 (second): video.mp4 is the output of another job"
+		);
+	}
+
+	#[test]
+	fn allows_jobs_without_output() {
+		let batch = Batch::parse(
+			r#"
+			[[job]]
+			url = "https://example.com"
+
+			[[job]]
+			url = "https://example.com"
+			"#,
+		);
+
+		assert!(batch.is_ok());
+	}
+
+	#[test]
+	fn rejects_waits_when_recording_from_start() {
+		for wait in [
+			"wait_for_selector = \"main\"",
+			"wait_for_function = \"window.ready\"",
+			"wait_for_network_idle = 500",
+			"delay = 100",
+		] {
+			let source = format!(
+				"[[job]]\nurl = \"https://example.com\"\nrecord_from_start = true\n{}\n",
+				wait
+			);
+
+			let err = Batch::parse(&source).unwrap_err();
+			assert!(
+				err.to_string().contains("record_from_start"),
+				"{}: {}",
+				wait,
+				err
+			);
+		}
+
+		let source = "[[job]]\nurl = \"https://example.com\"\nrecord_from_start = true\n";
+		assert!(Batch::parse(source).is_ok());
+	}
+
+	#[test]
+	fn job_options_override_the_recorder() {
+		let batch = Batch::parse(
+			r#"
+			[[job]]
+			url = "https://example.com"
+			jpeg_quality = 60
+			max_capture_width = 640
+			every_nth_frame = 2
+			on_backlog = "drop-newest"
+			max_queued_frames = 4
+			max_fps = 10
+			colors = 64
+			wait_timeout = 2.5
+			"#,
+		)
+		.unwrap();
+
+		let mut defaults = RecorderOptions::default();
+		defaults.recording.capture.max_height = Some(480);
+		defaults.recording.animation.max_fps = Some(20);
+		let recorder = Recorder::new(defaults);
+
+		let job = &batch.jobs[0];
+		let url = job.url.parse().unwrap();
+		let options = batch.recording_options(&recorder, job, &url).unwrap();
+
+		assert_eq!(options.capture.jpeg_quality, Some(60));
+		assert_eq!(options.capture.max_width, Some(640));
+		assert_eq!(options.capture.max_height, Some(480));
+		assert_eq!(options.capture.every_nth_frame, Some(2));
+		assert_eq!(options.on_backlog, BacklogPolicy::DropNewest);
+		assert_eq!(options.max_queued_frames, Some(4));
+		assert_eq!(options.animation.max_fps, Some(10));
+		assert_eq!(options.animation.colors, Some(64));
+		assert_eq!(options.wait.timeout, Some(Duration::from_millis(2500)));
+		assert!(options.no_clobber);
+	}
+}
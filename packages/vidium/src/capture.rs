@@ -0,0 +1,296 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chromiumoxide::cdp::browser_protocol::emulation::{
+	EventVirtualTimeBudgetExpired, SetVirtualTimePolicyParams, VirtualTimePolicy,
+};
+use chromiumoxide::cdp::browser_protocol::page::{
+	CaptureScreenshotFormat, CaptureScreenshotParams, EventScreencastFrame,
+	ScreencastFrameAckParams, StartScreencastFormat, StartScreencastParams, StopScreencastParams,
+};
+use chromiumoxide::listeners::EventStream;
+use chromiumoxide::{Binary, Page};
+use futures::StreamExt;
+
+use crate::recording::RecordingOptions;
+use crate::Error;
+
+/// How long to wait for frames that are still in flight after the screencast is stopped.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Image format of the captured frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaptureFormat {
+	/// Smaller and faster to transfer, but lossy.
+	#[default]
+	Jpeg,
+	/// Lossless, keeps text sharp.
+	Png,
+}
+
+impl CaptureFormat {
+	pub(crate) fn image_format(self) -> image::ImageFormat {
+		match self {
+			CaptureFormat::Jpeg => image::ImageFormat::Jpeg,
+			CaptureFormat::Png => image::ImageFormat::Png,
+		}
+	}
+}
+
+/// Parameters of `Page.startScreencast`.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureOptions {
+	pub format: CaptureFormat,
+	/// Compression quality from 0 to 100, jpeg only.
+	pub jpeg_quality: Option<u8>,
+	/// Maximum width of the captured frames, the page is scaled down to fit.
+	pub max_width: Option<u32>,
+	/// Maximum height of the captured frames, the page is scaled down to fit.
+	pub max_height: Option<u32>,
+	/// Send every n-th frame only.
+	pub every_nth_frame: Option<u32>,
+}
+
+impl CaptureOptions {
+	/// Size of the frames captured from a page of the given size, scaled down to the limits.
+	pub(crate) fn fit(&self, (width, height): (u32, u32)) -> (u32, u32) {
+		let scale = [
+			self.max_width.map(|max| f64::from(max) / f64::from(width)),
+			self.max_height
+				.map(|max| f64::from(max) / f64::from(height)),
+		]
+		.into_iter()
+		.flatten()
+		.fold(1.0, f64::min);
+
+		(
+			((f64::from(width) * scale).round() as u32).max(1),
+			((f64::from(height) * scale).round() as u32).max(1),
+		)
+	}
+
+	/// Whether the size or the rate of the captured frames is limited.
+	pub(crate) fn is_limited(&self) -> bool {
+		self.max_width.is_some() || self.max_height.is_some() || self.every_nth_frame.is_some()
+	}
+}
+
+/// A frame received from the page.
+pub(crate) enum Frame {
+	/// A frame pushed by the screencast as the page repaints.
+	Screencast(Arc<EventScreencastFrame>),
+	/// A screenshot taken one frame of the virtual clock after the previous one.
+	Captured(Binary),
+}
+
+impl Frame {
+	/// Screencast timestamp in seconds, frames captured with the virtual clock have none.
+	pub(crate) fn timestamp(&self) -> Option<f64> {
+		match self {
+			Frame::Screencast(item) => item.metadata.timestamp.as_ref().map(|ts| *ts.inner()),
+			Frame::Captured(_) => None,
+		}
+	}
+}
+
+/// Where the frames come from.
+pub(crate) enum Source {
+	/// `Page.startScreencast`, frames are timed with the wall clock.
+	Screencast {
+		page: Page,
+		listener: EventStream<EventScreencastFrame>,
+	},
+	/// Screenshots taken after advancing the virtual clock of the page by exactly one frame.
+	VirtualTime(VirtualTime),
+}
+
+impl Source {
+	pub(crate) async fn start(page: &Page, options: &RecordingOptions) -> Result<Self, Error> {
+		if options.deterministic {
+			let fps = options
+				.fps
+				.filter(|fps| *fps > 0)
+				.ok_or("deterministic recording requires a frame rate")?;
+
+			return Ok(Source::VirtualTime(
+				VirtualTime::start(page, fps, options.duration, &options.capture).await?,
+			));
+		}
+
+		let capture = &options.capture;
+
+		let mut params = StartScreencastParams::builder()
+			.every_nth_frame(capture.every_nth_frame.unwrap_or(1))
+			.format(match capture.format {
+				CaptureFormat::Jpeg => StartScreencastFormat::Jpeg,
+				CaptureFormat::Png => StartScreencastFormat::Png,
+			});
+
+		if let (CaptureFormat::Jpeg, Some(quality)) = (capture.format, capture.jpeg_quality) {
+			params = params.quality(quality);
+		}
+
+		if let Some(max_width) = capture.max_width {
+			params = params.max_width(max_width);
+		}
+
+		if let Some(max_height) = capture.max_height {
+			params = params.max_height(max_height);
+		}
+
+		// subscribe before starting the screencast so that the first frames are not lost
+		let listener = page.event_listener::<EventScreencastFrame>().await?;
+
+		page.execute(params.build()).await?;
+
+		Ok(Source::Screencast {
+			page: page.clone(),
+			listener,
+		})
+	}
+
+	/// Returns `None` when there are no more frames.
+	pub(crate) async fn next(&mut self) -> Option<Result<Frame, Error>> {
+		match self {
+			Source::Screencast { listener, .. } => {
+				listener.next().await.map(Frame::Screencast).map(Ok)
+			}
+			Source::VirtualTime(clock) => clock.next().await,
+		}
+	}
+
+	/// Tells the browser that the frame is processed and the next one can be sent.
+	pub(crate) async fn ack(&self, frame: &Frame) -> Result<(), Error> {
+		if let (Source::Screencast { page, .. }, Frame::Screencast(item)) = (self, frame) {
+			page.execute(
+				ScreencastFrameAckParams::builder()
+					.session_id(item.session_id)
+					.build()?,
+			)
+			.await?;
+		}
+
+		Ok(())
+	}
+
+	/// Stops capturing and returns the frames that were already sent by the browser.
+	pub(crate) async fn stop(self, drain: bool) -> Vec<Frame> {
+		let mut frames = Vec::new();
+
+		match self {
+			Source::Screencast { page, mut listener } => {
+				let _ = page.execute(StopScreencastParams::default()).await;
+
+				if drain {
+					while let Ok(Some(item)) =
+						tokio::time::timeout(DRAIN_TIMEOUT, listener.next()).await
+					{
+						frames.push(Frame::Screencast(item));
+					}
+				}
+			}
+			Source::VirtualTime(clock) => clock.stop().await,
+		}
+
+		frames
+	}
+
+	pub(crate) fn is_deterministic(&self) -> bool {
+		matches!(self, Source::VirtualTime(_))
+	}
+}
+
+/// Drives the page with a virtual clock, so that every frame is captured no matter how slow
+/// the rest of the pipeline is.
+///
+/// The clock is paused with `Emulation.setVirtualTimePolicy` and advanced by one frame at a
+/// time; timers, `requestAnimationFrame`, `Date` and animations follow the virtual clock.
+pub(crate) struct VirtualTime {
+	page: Page,
+	budget_expired: EventStream<EventVirtualTimeBudgetExpired>,
+	fps: u32,
+	screenshot: CaptureScreenshotParams,
+	/// Stop after this much virtual time.
+	limit: Option<Duration>,
+	/// Index of the next captured frame.
+	frame: u64,
+}
+
+impl VirtualTime {
+	async fn start(
+		page: &Page,
+		fps: u32,
+		limit: Option<Duration>,
+		capture: &CaptureOptions,
+	) -> Result<Self, Error> {
+		let mut screenshot = CaptureScreenshotParams::builder().format(match capture.format {
+			CaptureFormat::Jpeg => CaptureScreenshotFormat::Jpeg,
+			CaptureFormat::Png => CaptureScreenshotFormat::Png,
+		});
+
+		if let (CaptureFormat::Jpeg, Some(quality)) = (capture.format, capture.jpeg_quality) {
+			screenshot = screenshot.quality(quality);
+		}
+
+		let budget_expired = page
+			.event_listener::<EventVirtualTimeBudgetExpired>()
+			.await?;
+
+		page.execute(SetVirtualTimePolicyParams::new(VirtualTimePolicy::Pause))
+			.await?;
+
+		Ok(VirtualTime {
+			page: page.clone(),
+			budget_expired,
+			fps,
+			screenshot: screenshot.build(),
+			limit,
+			frame: 0,
+		})
+	}
+
+	async fn next(&mut self) -> Option<Result<Frame, Error>> {
+		let position = Duration::from_nanos(
+			(u128::from(self.frame) * 1_000_000_000 / u128::from(self.fps)) as u64,
+		);
+
+		if self.limit.is_some_and(|limit| position >= limit) {
+			tracing::info!("recording duration reached");
+			return None;
+		}
+
+		Some(self.capture().await)
+	}
+
+	async fn capture(&mut self) -> Result<Frame, Error> {
+		// the first frame shows the page as it is, every next one is a frame later
+		if self.frame > 0 {
+			let mut advance = SetVirtualTimePolicyParams::new(VirtualTimePolicy::Advance);
+			advance.budget = Some(1000.0 / f64::from(self.fps));
+
+			self.page.execute(advance).await?;
+			self.budget_expired
+				.next()
+				.await
+				.ok_or("the page was closed while advancing the virtual time")?;
+		}
+
+		let screenshot = self.page.execute(self.screenshot.clone()).await?;
+
+		self.frame += 1;
+
+		Ok(Frame::Captured(screenshot.result.data))
+	}
+
+	/// Unpauses the virtual clock.
+	///
+	/// There is no way back to real time: with the `advance` policy the clock keeps running,
+	/// but pending timers fire as soon as the page is idle instead of when they are due.
+	async fn stop(self) {
+		let _ = self
+			.page
+			.execute(SetVirtualTimePolicyParams::new(VirtualTimePolicy::Advance))
+			.await;
+	}
+}
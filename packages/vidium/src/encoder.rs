@@ -0,0 +1,394 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use chromiumoxide::cdp::browser_protocol::page::ScreencastFrameMetadata;
+use ffmpeg_next::format::Pixel;
+use image::imageops::{self, FilterType};
+use image::RgbImage;
+
+use crate::animation::{AnimationEncoder, AnimationOptions};
+use crate::frames::FrameSequence;
+use crate::pipeline::Decoded;
+use crate::timing::{Timing, TimingCorrections};
+use crate::video::{Encoding, VideoEncoder};
+use crate::Error;
+
+/// Places decoded frames on the video timeline and encodes them.
+///
+/// Without a frame rate, frames are encoded as they come and the timeline follows the
+/// screencast timestamps (variable frame rate). With a frame rate, frames are placed on
+/// a fixed grid: the last frame is repeated while the page doesn't repaint, and only
+/// the latest of several frames that fall into the same slot is kept.
+///
+/// The video size is taken from the first frame unless it is known upfront. Frames of
+/// a different size are scaled to fit the video and letterboxed.
+pub(crate) struct FrameEncoder {
+	destination: PathBuf,
+	target: Target,
+	/// Created once the size of the video is known.
+	sink: Option<Sink>,
+	/// Width and height of the video.
+	size: Option<(u32, u32)>,
+	fps: Option<u32>,
+	/// Stop encoding after this many frames.
+	max_frames: Option<u64>,
+	timing: Timing,
+	/// Timeline position of the previous frame and the time spent in pause until then.
+	prev_position: Option<(Duration, Duration)>,
+	/// Position of the last received frame.
+	elapsed: Duration,
+	/// When the last frame was received.
+	last_frame_at: Option<Instant>,
+	paused_at: Option<Instant>,
+	/// Total time spent in pause, cut out of the video.
+	paused_for: Duration,
+	/// The latest frame, waiting for the next slot of the grid.
+	pending: Option<RgbImage>,
+	/// Index of the next slot of the grid.
+	next_slot: u64,
+	/// Number of encoded frames.
+	frames: u64,
+}
+
+impl FrameEncoder {
+	pub(crate) fn new(
+		destination: PathBuf,
+		target: Target,
+		size: Option<(u32, u32)>,
+		fps: Option<u32>,
+		max_frames: Option<u64>,
+	) -> Result<Self, Error> {
+		let mut encoder = FrameEncoder {
+			destination,
+			target,
+			sink: None,
+			size: None,
+			fps: fps.filter(|fps| *fps > 0),
+			max_frames,
+			timing: Timing::default(),
+			prev_position: None,
+			elapsed: Duration::ZERO,
+			last_frame_at: None,
+			paused_at: None,
+			paused_for: Duration::ZERO,
+			pending: None,
+			next_slot: 0,
+			frames: 0,
+		};
+
+		// fail early when the size is known
+		if let Some((width, height)) = size {
+			encoder.open(width, height)?;
+		}
+
+		Ok(encoder)
+	}
+
+	fn open(&mut self, width: u32, height: u32) -> Result<(), Error> {
+		// yuv420p needs even dimensions
+		let even = match &self.target {
+			Target::Video(encoding) => encoding.pixel_format == Pixel::YUV420P,
+			Target::Webp(_) => true,
+			Target::Gif(_) | Target::Frames => false,
+		};
+		let (width, height) = if even {
+			((width & !1).max(2), (height & !1).max(2))
+		} else {
+			(width, height)
+		};
+
+		self.sink = Some(match &self.target {
+			Target::Video(encoding) => Sink::Video(VideoEncoder::new(
+				&self.destination,
+				encoding,
+				width,
+				height,
+				self.fps,
+			)?),
+			Target::Gif(options) => {
+				Sink::Animation(AnimationEncoder::gif(&self.destination, options))
+			}
+			Target::Webp(options) => Sink::Animation(AnimationEncoder::webp(
+				&self.destination,
+				options,
+				width,
+				height,
+			)?),
+			Target::Frames => Sink::Frames(FrameSequence::new(&self.destination)),
+		});
+		self.size = Some((width, height));
+
+		Ok(())
+	}
+
+	/// Converts a decoded image into a frame of the video, opening the encoder on the first one.
+	fn prepare(&mut self, image: RgbImage) -> Result<RgbImage, Error> {
+		let (width, height) = match self.size {
+			Some(size) => size,
+			None => {
+				self.open(image.width(), image.height())?;
+				self.size.unwrap_or(image.dimensions())
+			}
+		};
+
+		Ok(fit(image, width, height))
+	}
+
+	fn sink(&mut self) -> Result<&mut Sink, Error> {
+		self.sink
+			.as_mut()
+			.ok_or_else(|| "the encoder is not open".into())
+	}
+
+	/// Position in the video of something that happened `at`, the page may not have repainted since.
+	pub(crate) fn position_at(&self, at: Instant) -> Duration {
+		let Some(last_frame_at) = self.last_frame_at else {
+			return self.elapsed;
+		};
+
+		// pauses since the last frame are cut out, including one that is still going on
+		let paused_since = self
+			.prev_position
+			.map_or(Duration::ZERO, |(_, paused_for)| {
+				self.paused_for.saturating_sub(paused_for)
+			});
+		let paused_now = self.paused_at.map_or(Duration::ZERO, |paused_at| {
+			at.saturating_duration_since(paused_at)
+		});
+
+		self.elapsed
+			+ at.saturating_duration_since(last_frame_at)
+				.saturating_sub(paused_since + paused_now)
+	}
+
+	pub(crate) fn corrections(&self) -> TimingCorrections {
+		self.timing.corrections()
+	}
+
+	/// Whether the maximum number of frames is encoded.
+	pub(crate) fn is_full(&self) -> bool {
+		self.max_frames.is_some_and(|max| self.frames >= max)
+	}
+
+	/// `at` is when the pause was requested, frames are processed a bit later.
+	pub(crate) fn pause(&mut self, at: Instant) {
+		if self.paused_at.is_none() {
+			tracing::info!("recording paused");
+			self.paused_at = Some(at);
+		}
+	}
+
+	pub(crate) fn resume(&mut self, at: Instant) {
+		if let Some(paused_at) = self.paused_at.take() {
+			tracing::info!("recording resumed");
+			self.paused_for += at.saturating_duration_since(paused_at);
+		}
+	}
+
+	/// Frames received while the recording is paused are skipped.
+	pub(crate) fn encode(&mut self, decoded: Decoded) -> Result<(), Error> {
+		if self.paused_at.is_some() || self.is_full() {
+			return Ok(());
+		}
+
+		let Decoded {
+			image,
+			metadata,
+			received_at,
+		} = decoded;
+
+		let frame = self.prepare(image)?;
+
+		match metadata {
+			Some(metadata) => self.place(metadata, received_at, frame),
+			None => {
+				// captured frames are exactly one frame apart, they go straight into the next slot
+				let slot = self.next_slot;
+				self.fill(&frame, slot + 1)?;
+				self.elapsed = slot_time(slot, self.fps.unwrap_or(1));
+				Ok(())
+			}
+		}
+	}
+
+	/// Whether frames are placed on the grid of the frame rate.
+	fn on_grid(&self) -> bool {
+		// image sequences keep every captured frame
+		self.fps.is_some() && !matches!(self.target, Target::Frames)
+	}
+
+	fn place(
+		&mut self,
+		metadata: ScreencastFrameMetadata,
+		received_at: Instant,
+		frame: RgbImage,
+	) -> Result<(), Error> {
+		let timestamp = metadata.timestamp.as_ref().map(|ts| *ts.inner());
+
+		let position = self.timing.next(timestamp, received_at);
+
+		if let Some((prev, paused_for)) = self.prev_position {
+			// pauses since the previous frame are cut out
+			let pause = self.paused_for.saturating_sub(paused_for);
+			self.elapsed += position.saturating_sub(prev).saturating_sub(pause);
+		}
+
+		self.prev_position = Some((position, self.paused_for));
+		self.last_frame_at = Some(received_at);
+
+		match self.fps.filter(|_| self.on_grid()) {
+			Some(fps) => {
+				let slot = slot_at(self.elapsed, fps);
+				if let Some(pending) = self.pending.take() {
+					if self.next_slot >= slot {
+						tracing::debug!("frame replaced by a newer one in the same slot");
+					}
+					self.fill(&pending, slot)?;
+				}
+				self.pending = Some(frame);
+			}
+			None => {
+				let time = std::time::Instant::now();
+				let position = self.elapsed;
+				self.sink()?.encode(&frame, position, Some(&metadata))?;
+				self.frames += 1;
+
+				tracing::info!("{}: {}ms", "encoder::encode", time.elapsed().as_millis());
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Writes `frame` into every slot before `until`, starting from the next free one.
+	fn fill(&mut self, frame: &RgbImage, until: u64) -> Result<(), Error> {
+		let fps = self.fps.unwrap_or(1);
+
+		while self.next_slot < until && !self.is_full() {
+			let time = std::time::Instant::now();
+			let position = slot_time(self.next_slot, fps);
+			self.sink()?.encode(frame, position, None)?;
+
+			tracing::info!("{}: {}ms", "encoder::encode", time.elapsed().as_millis());
+
+			self.next_slot += 1;
+			self.frames += 1;
+		}
+
+		Ok(())
+	}
+
+	/// Flushes the pending frame and finalizes the video, returns the number of encoded frames.
+	pub(crate) fn finish(mut self) -> Result<u64, Error> {
+		// the page didn't repaint since the last frame, keep showing it until the end
+		let idle = match (self.last_frame_at, self.paused_at) {
+			(Some(last_frame_at), None) => last_frame_at.elapsed(),
+			_ => Duration::ZERO,
+		};
+
+		let mut end = self.elapsed + idle;
+
+		if let Some(fps) = self.fps.filter(|_| self.on_grid()) {
+			if let Some(pending) = self.pending.take() {
+				let until = slot_at(end, fps).max(self.next_slot + 1);
+				self.fill(&pending, until)?;
+			}
+
+			end = slot_time(self.next_slot, fps);
+		}
+
+		match self.sink {
+			Some(sink) => sink.finish(end)?,
+			None => tracing::warn!("no frames were captured, the video was not created"),
+		}
+
+		Ok(self.frames)
+	}
+}
+
+/// What the frames are written as.
+#[derive(Debug, Clone)]
+pub(crate) enum Target {
+	Video(Encoding),
+	Gif(AnimationOptions),
+	Webp(AnimationOptions),
+	/// PNG files in a directory.
+	Frames,
+}
+
+/// Writes the frames placed on the timeline.
+enum Sink {
+	Video(VideoEncoder),
+	Animation(AnimationEncoder),
+	Frames(FrameSequence),
+}
+
+impl Sink {
+	/// `metadata` is only known for frames placed as they come.
+	fn encode(
+		&mut self,
+		image: &RgbImage,
+		position: Duration,
+		metadata: Option<&ScreencastFrameMetadata>,
+	) -> Result<(), Error> {
+		match self {
+			Sink::Video(encoder) => encoder.encode(image, position),
+			Sink::Animation(encoder) => encoder.encode(image, position),
+			Sink::Frames(sequence) => sequence.encode(image, position, metadata),
+		}
+	}
+
+	/// Finalizes the file, `end` is when the last frame stops being shown.
+	fn finish(self, end: Duration) -> Result<(), Error> {
+		match self {
+			Sink::Video(mut encoder) => encoder.finish(),
+			Sink::Animation(encoder) => encoder.finish(end),
+			Sink::Frames(sequence) => sequence.finish(),
+		}
+	}
+}
+
+/// Index of the slot that contains `position` on a grid of `fps` slots per second.
+pub(crate) fn slot_at(position: Duration, fps: u32) -> u64 {
+	(position.as_nanos() * u128::from(fps) / 1_000_000_000) as u64
+}
+
+/// Start of the slot with the given index on a grid of `fps` slots per second.
+fn slot_time(slot: u64, fps: u32) -> Duration {
+	Duration::from_nanos((u128::from(slot) * 1_000_000_000 / u128::from(fps)) as u64)
+}
+
+/// Scales `image` to fit into `width`x`height` keeping its aspect ratio, the rest is filled with black.
+fn fit(image: RgbImage, width: u32, height: u32) -> RgbImage {
+	if image.dimensions() == (width, height) {
+		return image;
+	}
+
+	let scale = f64::min(
+		f64::from(width) / f64::from(image.width()),
+		f64::from(height) / f64::from(image.height()),
+	);
+
+	let scaled_width = ((f64::from(image.width()) * scale).round() as u32).clamp(1, width);
+	let scaled_height = ((f64::from(image.height()) * scale).round() as u32).clamp(1, height);
+
+	tracing::debug!(
+		"scaling {}x{} frame to {}x{}",
+		image.width(),
+		image.height(),
+		scaled_width,
+		scaled_height
+	);
+
+	let scaled = imageops::resize(&image, scaled_width, scaled_height, FilterType::Triangle);
+
+	let mut canvas = RgbImage::new(width, height);
+	imageops::replace(
+		&mut canvas,
+		&scaled,
+		i64::from((width - scaled_width) / 2),
+		i64::from((height - scaled_height) / 2),
+	);
+
+	canvas
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+
+use crate::Error;
+
+/// Why a recording failed.
+///
+/// Each variant has its own exit code, so that scripts running vidium can tell a flaky
+/// browser apart from a bad argument.
+#[derive(Debug)]
+pub enum VidiumError {
+	/// An option or an input file is invalid.
+	InvalidArgument(Error),
+	/// The browser could not be launched, or it went away.
+	Browser(Error),
+	/// The page could not be opened.
+	Navigation(Error),
+	/// Frames could not be captured from the page.
+	Capture(Error),
+	/// A captured frame could not be decoded.
+	Decode(Error),
+	/// The video could not be encoded or written.
+	Encode(Error),
+	/// A step of the scenario failed.
+	Scenario(Error),
+	/// The page didn't meet the conditions to start the recording in time.
+	Wait(Error),
+}
+
+impl VidiumError {
+	/// Exit code of the command line tool.
+	///
+	/// | code | error |
+	/// |------|-------|
+	/// | 2    | [`InvalidArgument`](VidiumError::InvalidArgument) |
+	/// | 3    | [`Browser`](VidiumError::Browser) |
+	/// | 4    | [`Navigation`](VidiumError::Navigation) |
+	/// | 5    | [`Capture`](VidiumError::Capture) |
+	/// | 6    | [`Decode`](VidiumError::Decode) |
+	/// | 7    | [`Encode`](VidiumError::Encode) |
+	/// | 8    | [`Scenario`](VidiumError::Scenario) |
+	/// | 9    | [`Wait`](VidiumError::Wait) |
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			VidiumError::InvalidArgument(_) => 2,
+			VidiumError::Browser(_) => 3,
+			VidiumError::Navigation(_) => 4,
+			VidiumError::Capture(_) => 5,
+			VidiumError::Decode(_) => 6,
+			VidiumError::Encode(_) => 7,
+			VidiumError::Scenario(_) => 8,
+			VidiumError::Wait(_) => 9,
+		}
+	}
+
+	fn inner(&self) -> &Error {
+		match self {
+			VidiumError::InvalidArgument(e)
+			| VidiumError::Browser(e)
+			| VidiumError::Navigation(e)
+			| VidiumError::Capture(e)
+			| VidiumError::Decode(e)
+			| VidiumError::Encode(e)
+			| VidiumError::Scenario(e)
+			| VidiumError::Wait(e) => e,
+		}
+	}
+}
+
+impl fmt::Display for VidiumError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let context = match self {
+			VidiumError::InvalidArgument(_) => "invalid argument",
+			VidiumError::Browser(_) => "browser error",
+			VidiumError::Navigation(_) => "failed to open the page",
+			VidiumError::Capture(_) => "failed to capture the page",
+			VidiumError::Decode(_) => "failed to decode a frame",
+			VidiumError::Encode(_) => "failed to encode the video",
+			VidiumError::Scenario(_) => "scenario failed",
+			VidiumError::Wait(_) => "the page is not ready to be recorded",
+		};
+
+		write!(f, "{}: {}", context, self.inner())
+	}
+}
+
+impl std::error::Error for VidiumError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		Some(self.inner().as_ref())
+	}
+}
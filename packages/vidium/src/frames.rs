@@ -0,0 +1,98 @@
+//! Frames written as numbered PNG files with a JSON manifest.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chromiumoxide::cdp::browser_protocol::page::ScreencastFrameMetadata;
+use image::{ImageFormat, RgbImage};
+use serde::Serialize;
+
+use crate::Error;
+
+/// Name of the manifest, written next to the frames.
+const MANIFEST: &str = "frames.json";
+
+/// Writes every frame into a directory, `000000.png`, `000001.png`, ...
+///
+/// `frames.json` lists the frames with their position in the recording and the screencast
+/// metadata, so that they can be matched with the state of the page.
+pub(crate) struct FrameSequence {
+	directory: PathBuf,
+	frames: Vec<FrameEntry>,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+	frames: &'a [FrameEntry],
+}
+
+#[derive(Serialize)]
+struct FrameEntry {
+	/// File name of the frame.
+	file: String,
+	/// Seconds since the start of the recording, pauses are cut out.
+	position: f64,
+	/// Screencast timestamp in seconds, frames captured with the virtual clock have none.
+	timestamp: Option<f64>,
+	/// Scroll offsets, page scale factor and device size when the frame was captured.
+	metadata: Option<ScreencastFrameMetadata>,
+}
+
+impl FrameSequence {
+	pub(crate) fn new(directory: &Path) -> FrameSequence {
+		tracing::info!("writing frames to {}", directory.display());
+
+		FrameSequence {
+			directory: directory.to_path_buf(),
+			frames: Vec::new(),
+		}
+	}
+
+	pub(crate) fn encode(
+		&mut self,
+		image: &RgbImage,
+		position: Duration,
+		metadata: Option<&ScreencastFrameMetadata>,
+	) -> Result<(), Error> {
+		let file = format!("{:06}.png", self.frames.len());
+		let path = self.directory.join(&file);
+
+		image
+			.save_with_format(&path, ImageFormat::Png)
+			.map_err(|e| format!("can't write {}: {}", path.display(), e))?;
+
+		self.frames.push(FrameEntry {
+			file,
+			position: position.as_secs_f64(),
+			timestamp: metadata
+				.and_then(|metadata| metadata.timestamp.as_ref().map(|ts| *ts.inner())),
+			metadata: metadata.cloned(),
+		});
+
+		Ok(())
+	}
+
+	/// Writes the manifest.
+	pub(crate) fn finish(self) -> Result<(), Error> {
+		let path = self.directory.join(MANIFEST);
+		let file =
+			File::create(&path).map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+
+		serde_json::to_writer_pretty(
+			BufWriter::new(file),
+			&Manifest {
+				frames: &self.frames,
+			},
+		)?;
+
+		tracing::info!(
+			"{} frames written to {}",
+			self.frames.len(),
+			self.directory.display()
+		);
+
+		Ok(())
+	}
+}
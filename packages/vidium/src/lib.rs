@@ -0,0 +1,50 @@
+//! Record video from a Chrome/Chromium tab.
+//!
+//! Uses the [Page.startScreencast](https://chromedevtools.github.io/devtools-protocol/tot/Page/#method-startScreencast)
+//! Chrome DevTools protocol method and encodes the received frames with ffmpeg.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), vidium::Error> {
+//! use vidium::{Recorder, RecorderOptions};
+//!
+//! let recorder = Recorder::new(RecorderOptions::default());
+//! let recording = recorder.start("https://google.com".parse()?).await?;
+//!
+//! tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+//! recording.stop().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod animation;
+pub mod batch;
+mod capture;
+mod encoder;
+mod error;
+mod frames;
+mod output;
+mod pipeline;
+mod recorder;
+mod recording;
+pub mod scenario;
+mod script;
+mod timing;
+mod video;
+mod wait;
+
+pub use animation::{AnimationOptions, Dither};
+pub use batch::Batch;
+pub use capture::{CaptureFormat, CaptureOptions};
+pub use error::VidiumError;
+pub use output::{Format, OutputTemplate};
+pub use pipeline::{BacklogPolicy, QueueDepths};
+pub use recorder::{LaunchOptions, Recorder, RecorderOptions};
+pub use recording::{record_page, Marker, RecordingHandle, RecordingOptions, RecordingSummary};
+pub use scenario::Scenario;
+pub use timing::TimingCorrections;
+pub use video::{Codec, PixelFormat, Tune, VideoOptions};
+pub use url::Url;
+pub use wait::WaitOptions;
+
+/// The underlying cause of a [`VidiumError`].
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -1,18 +1,23 @@
 use std::path::PathBuf;
 use std::time::Duration;
 
-use base64::Engine;
-use chromiumoxide::browser::{Browser, BrowserConfig};
-use futures::StreamExt;
-use video_rs::{Encoder, EncoderSettings, Locator, Time, Url};
-
 use clap::Parser;
+use vidium::{
+	AnimationOptions, BacklogPolicy, CaptureFormat, CaptureOptions, Codec, Dither, Format,
+	LaunchOptions, OutputTemplate, PixelFormat, Recorder, RecorderOptions, RecordingOptions,
+	Scenario, Tune, Url, VideoOptions, VidiumError, WaitOptions,
+};
 
 #[derive(Parser, Debug)]
 #[command()]
 struct Encode {
-	#[arg(long)]
-	url: Url,
+	/// Page to open in a new tab
+	#[arg(long, required_unless_present = "target")]
+	url: Option<Url>,
+
+	/// Record an open tab whose url or title contains this text instead of opening --url
+	#[arg(long, requires = "browser_ws", conflicts_with = "url")]
+	target: Option<String>,
 
 	#[arg(long, default_value_t = 800)]
 	width: u32,
@@ -20,132 +25,583 @@ struct Encode {
 	#[arg(long, default_value_t = 600)]
 	height: u32,
 
+	/// Device pixel ratio of the page, also defines the size of the video
+	/// (taken from the first captured frame by default)
+	#[arg(long, value_parser = parse_scale)]
+	device_scale_factor: Option<f64>,
+
+	#[command(flatten)]
+	browser: BrowserArgs,
+
+	#[arg(long)]
+	output: Option<PathBuf>,
+
+	/// Output path with placeholders, used when --output is not set:
+	/// {host}, {path_slug}, {width}, {height}, {timestamp} and {ext}
+	#[arg(long, conflicts_with = "output")]
+	output_template: Option<OutputTemplate>,
+
+	/// Add a -1, -2, ... suffix to the file name instead of overwriting an existing video
+	#[arg(long, default_value_t = false)]
+	no_clobber: bool,
+
+	/// What the recording is written as, taken from the extension of --output by default
+	/// (animations for .gif and .webp, a video otherwise). png-seq writes a directory of frames
+	#[arg(long, value_enum)]
+	format: Option<Format>,
+
+	/// Video codec, taken from the extension of --output by default (vp9 for .webm, h264 otherwise)
+	#[arg(long, value_enum)]
+	codec: Option<Codec>,
+
+	/// Constant quality, lower is better: 0-51 for h264, 0-63 for vp9 and av1
+	#[arg(long, value_parser = clap::value_parser!(u8).range(0..=63))]
+	crf: Option<u8>,
+
+	/// Encoder speed: ultrafast to veryslow for h264, 0-8 for vp9 and libaom, 0-13 for SVT-AV1
+	#[arg(long)]
+	preset: Option<String>,
+
+	/// Target bitrate, like 2M or 800k, replaces the constant quality unless --crf is set too
+	#[arg(long, value_parser = parse_bitrate)]
+	bitrate: Option<u64>,
+
+	/// Maximum bitrate, like 4M
+	#[arg(long, value_parser = parse_bitrate)]
+	max_bitrate: Option<u64>,
+
+	/// Frames between keyframes, 2 seconds by default
+	#[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+	gop: Option<u32>,
+
+	/// Tune the encoder for mostly static pages or for fast motion
+	#[arg(long, value_enum)]
+	tune: Option<Tune>,
+
+	/// Pixel format of the video, yuv444p and rgb24 keep colors sharp but play in fewer players
+	#[arg(long, value_enum, default_value = "yuv420p")]
+	pixel_format: PixelFormat,
+
+	/// Encode without loss: qp 0 for h264, lossless vp9, or FFV1 for .mkv outputs
+	#[arg(long)]
+	lossless: bool,
+
+	/// Frame rate cap of .gif and .webp animations, closer frames replace each other
+	#[arg(long, value_parser = clap::value_parser!(u32).range(1..), default_value = "15")]
+	max_fps: u32,
+
+	/// How colors missing from the .gif palette are rendered
+	#[arg(long, value_enum, default_value_t = Dither::FloydSteinberg)]
+	dither: Dither,
+
+	/// Size of the .gif palette
+	#[arg(long, value_parser = clap::value_parser!(u16).range(2..=256), default_value = "256")]
+	colors: u16,
+
+	/// Image format of the captured frames
+	#[arg(long, value_enum, default_value_t = CaptureFormat::Jpeg)]
+	capture_format: CaptureFormat,
+
+	/// Compression quality of the captured jpeg frames, from 0 to 100
+	#[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+	jpeg_quality: Option<u8>,
+
+	/// Maximum width of the captured frames
+	#[arg(long)]
+	max_capture_width: Option<u32>,
+
+	/// Maximum height of the captured frames
+	#[arg(long)]
+	max_capture_height: Option<u32>,
+
+	/// Capture every n-th frame only
+	#[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+	every_nth_frame: Option<u32>,
+
+	/// Start capturing on a blank page and only then navigate to --url,
+	/// so that the page load ends up in the video
+	#[arg(
+		long,
+		default_value_t = false,
+		conflicts_with_all = [
+			"target",
+			"wait_for_selector",
+			"wait_for_function",
+			"wait_for_network_idle",
+			"delay",
+		]
+	)]
+	record_from_start: bool,
+
+	/// Start recording once an element matching the css selector appears
+	#[arg(long)]
+	wait_for_selector: Option<String>,
+
+	/// Start recording once no network requests are in flight for this many milliseconds
+	#[arg(long, num_args = 0..=1, default_missing_value = "500", value_parser = parse_millis)]
+	wait_for_network_idle: Option<Duration>,
+
+	/// Start recording once the JavaScript expression is truthy
+	#[arg(long)]
+	wait_for_function: Option<String>,
+
+	/// Wait this many milliseconds before recording, after the other --wait-for conditions
+	#[arg(long, value_parser = parse_millis)]
+	delay: Option<Duration>,
+
+	/// Fail if a --wait-for condition is not met after this many seconds
+	#[arg(long, value_parser = parse_seconds, default_value = "30")]
+	wait_timeout: Duration,
+
+	/// Stop the recording after this many seconds
+	#[arg(long, value_parser = parse_seconds)]
+	duration: Option<Duration>,
+
+	/// Stop the recording after this many frames
+	#[arg(long)]
+	max_frames: Option<u64>,
+
+	/// Stop the recording when the page sends no new frames for this many seconds
+	#[arg(long, value_parser = parse_seconds)]
+	stop_after_idle: Option<Duration>,
+
+	/// JavaScript file evaluated in the page once the recording starts,
+	/// it can control the recording through `window.vidium`
+	#[arg(long)]
+	script: Option<PathBuf>,
+
+	/// YAML or JSON file with the steps to perform on the page while recording,
+	/// the recording stops when all steps are done
+	#[arg(long)]
+	scenario: Option<PathBuf>,
+
+	/// Produce a constant frame rate video, repeating the last frame while the page is static
+	#[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+	fps: Option<u32>,
+
+	/// Drive the page with a virtual clock, so that every frame is captured
+	/// no matter how slow the encoding is (requires --fps)
+	#[arg(
+		long,
+		default_value_t = false,
+		requires = "fps",
+		conflicts_with_all = ["max_capture_width", "max_capture_height", "every_nth_frame"]
+	)]
+	deterministic: bool,
+
+	/// What to do with new frames when the encoder falls behind
+	#[arg(long, value_enum, default_value_t = BacklogPolicy::Block)]
+	on_backlog: BacklogPolicy,
+
+	/// How many frames can wait to be decoded before --on-backlog applies
+	#[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+	max_queued_frames: Option<u64>,
+}
+
+/// How the browser is launched, or which one is connected to.
+#[derive(clap::Args, Debug)]
+struct BrowserArgs {
+	/// Connect to a running browser instead of launching one: a WebSocket debugger url
+	/// or an http endpoint like http://127.0.0.1:9222
+	#[arg(
+		long,
+		alias = "remote-debugging-url",
+		conflicts_with_all = [
+			"chrome_path",
+			"chrome_arg",
+			"user_data_dir",
+			"no_sandbox",
+			"incognito",
+			"extension",
+		]
+	)]
+	browser_ws: Option<String>,
+
 	#[arg(long, default_value_t = false)]
 	headless: bool,
 
+	/// Path to the Chrome/Chromium executable, detected automatically by default
 	#[arg(long)]
-	output: Option<PathBuf>,
+	chrome_path: Option<PathBuf>,
+
+	/// Extra command line argument of the browser, can be repeated
+	#[arg(long, allow_hyphen_values = true)]
+	chrome_arg: Vec<String>,
+
+	/// Browser profile directory, keeps cookies and logins between recordings
+	#[arg(long)]
+	user_data_dir: Option<PathBuf>,
+
+	/// Disable the browser sandbox, required when running as root in containers
+	#[arg(long, default_value_t = false)]
+	no_sandbox: bool,
+
+	/// Open the page in an incognito context
+	#[arg(long, default_value_t = false)]
+	incognito: bool,
+
+	/// Directory of an unpacked extension to load, can be repeated
+	#[arg(long)]
+	extension: Vec<PathBuf>,
+}
+
+impl BrowserArgs {
+	fn launch_options(self) -> (LaunchOptions, Option<String>) {
+		let launch = LaunchOptions {
+			chrome_path: self.chrome_path,
+			chrome_args: self.chrome_arg,
+			user_data_dir: self.user_data_dir,
+			no_sandbox: self.no_sandbox,
+			incognito: self.incognito,
+			extensions: self.extension,
+		};
+
+		(launch, self.browser_ws)
+	}
+}
+
+/// Record the jobs of a TOML manifest as parallel pages of one browser
+#[derive(Parser, Debug)]
+struct Batch {
+	/// TOML file with a [[job]] table per recording
+	manifest: PathBuf,
+
+	/// How many jobs are recorded at the same time
+	#[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+	concurrency: u64,
+
+	/// Default width of the pages
+	#[arg(long, default_value_t = 800)]
+	width: u32,
+
+	/// Default height of the pages
+	#[arg(long, default_value_t = 600)]
+	height: u32,
+
+	/// Default device pixel ratio of the pages
+	#[arg(long, value_parser = parse_scale)]
+	device_scale_factor: Option<f64>,
+
+	/// Output path of the jobs without an output, see `vidium encode --help`
+	#[arg(long)]
+	output_template: Option<OutputTemplate>,
+
+	/// Add a -1, -2, ... suffix to the file name instead of overwriting an existing video
+	#[arg(long, default_value_t = false)]
+	no_clobber: bool,
+
+	#[command(flatten)]
+	browser: BrowserArgs,
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+	let millis: u64 = value.parse().map_err(|e| format!("{e}"))?;
+	Ok(Duration::from_millis(millis))
+}
+
+/// Bits per second with an optional k or M suffix.
+fn parse_bitrate(value: &str) -> Result<u64, String> {
+	let (number, multiplier) = match value.char_indices().last() {
+		Some((at, 'k' | 'K')) => (&value[..at], 1_000.0),
+		Some((at, 'm' | 'M')) => (&value[..at], 1_000_000.0),
+		_ => (value, 1.0),
+	};
+
+	let number: f64 = number.parse().map_err(|e| format!("{e}"))?;
+	if !number.is_finite() || number <= 0.0 {
+		return Err(format!("{value} is not a positive bitrate"));
+	}
+
+	Ok((number * multiplier).round() as u64)
+}
+
+/// A device pixel ratio, a finite number above 0.
+fn parse_scale(value: &str) -> Result<f64, String> {
+	let scale: f64 = value.parse().map_err(|e| format!("{e}"))?;
+	if !scale.is_finite() || scale <= 0.0 {
+		return Err(format!("{value} is not above 0"));
+	}
+
+	Ok(scale)
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+	let seconds: f64 = value.parse().map_err(|e| format!("{e}"))?;
+	Duration::try_from_secs_f64(seconds).map_err(|e| format!("{e}"))
 }
 
 #[derive(Parser, Debug)]
 enum Args {
 	Encode(Encode),
+	Batch(Batch),
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
 	tracing_subscriber::fmt::init();
 
 	let args = Args::parse();
 
-	let Args::Encode(args) = args;
+	let result = match args {
+		Args::Encode(args) => encode(args).await,
+		Args::Batch(args) => batch(args).await,
+	};
+
+	match result {
+		Ok(None) => {}
+		Ok(Some(code)) => std::process::exit(code),
+		Err(e) => {
+			eprintln!("vidium: {}", e);
+			std::process::exit(e.exit_code());
+		}
+	}
+}
 
-	// create a `Browser` that spawns a `chromium` process running with UI (`with_head()`, headless is default)
-	// and the handler that drives the websocket etc.
-	let (mut browser, mut handler) = Browser::launch({
-		let mut builder = BrowserConfig::builder().window_size(args.width, args.height);
+/// Records the page, returns the exit code to use if the recording was stopped by a signal.
+async fn encode(args: Encode) -> Result<Option<i32>, VidiumError> {
+	let script = match &args.script {
+		Some(path) => Some(std::fs::read_to_string(path).map_err(|e| {
+			VidiumError::InvalidArgument(format!("can't read {}: {}", path.display(), e).into())
+		})?),
+		None => None,
+	};
+
+	let scenario = match &args.scenario {
+		Some(path) => Some(Scenario::load(path).map_err(|e| {
+			VidiumError::InvalidArgument(format!("can't load {}: {}", path.display(), e).into())
+		})?),
+		None => None,
+	};
+
+	let headless = args.browser.headless;
+	let (launch, connect) = args.browser.launch_options();
+
+	let recorder = Recorder::new(RecorderOptions {
+		width: args.width,
+		height: args.height,
+		device_scale_factor: args.device_scale_factor,
+		headless,
+		record_from_start: args.record_from_start,
+		launch,
+		connect,
+		recording: RecordingOptions {
+			output: args.output,
+			output_template: args.output_template,
+			no_clobber: args.no_clobber,
+			size: None,
+			capture: CaptureOptions {
+				format: args.capture_format,
+				jpeg_quality: args.jpeg_quality,
+				max_width: args.max_capture_width,
+				max_height: args.max_capture_height,
+				every_nth_frame: args.every_nth_frame,
+			},
+			format: args.format,
+			video: VideoOptions {
+				codec: args.codec,
+				crf: args.crf,
+				preset: args.preset,
+				bitrate: args.bitrate,
+				max_bitrate: args.max_bitrate,
+				gop: args.gop,
+				tune: args.tune,
+				pixel_format: args.pixel_format,
+				lossless: args.lossless,
+			},
+			animation: AnimationOptions {
+				max_fps: Some(args.max_fps),
+				dither: args.dither,
+				colors: Some(args.colors),
+			},
+			wait: WaitOptions {
+				selector: args.wait_for_selector,
+				network_idle: args.wait_for_network_idle,
+				function: args.wait_for_function,
+				delay: args.delay,
+				timeout: Some(args.wait_timeout),
+			},
+			navigate_to: None,
+			duration: args.duration,
+			max_frames: args.max_frames,
+			stop_after_idle: args.stop_after_idle,
+			script,
+			scenario,
+			fps: args.fps,
+			deterministic: args.deterministic,
+			on_backlog: args.on_backlog,
+			max_queued_frames: args.max_queued_frames.map(|max| max as usize),
+		},
+	});
 
-		if !args.headless {
-			builder = builder.with_head()
-		}
-		builder.build()?
-	})
-	.await?;
-
-	// spawn a new task that continuously polls the handler
-	let handle = tokio::task::spawn(async move {
-		while let Some(h) = handler.next().await {
-			if h.is_err() {
-				break;
-			}
+	let recording = match (&args.target, args.url) {
+		(Some(pattern), _) => recorder.start_target(pattern).await?,
+		(None, Some(url)) => recorder.start(url).await?,
+		(None, None) => {
+			return Err(VidiumError::InvalidArgument(
+				"either --url or --target is required".into(),
+			))
 		}
-	});
+	};
 
-	let page = browser.new_page(args.url.clone()).await?;
+	let (summary, signal) = recording.wait_or(shutdown_signal()).await?;
 
-	page.wait_for_navigation().await?;
+	for marker in &summary.markers {
+		println!(
+			"marker {}: {:.3}s",
+			marker.name,
+			marker.position.as_secs_f64()
+		);
+	}
+
+	Ok(signal)
+}
 
-	let _ = page
-		.execute(
-			chromiumoxide::cdp::browser_protocol::page::StartScreencastParams::builder()
-				.every_nth_frame(1)
-				.format(chromiumoxide::cdp::browser_protocol::page::StartScreencastFormat::Jpeg)
-				.build(),
+/// Records the jobs of the manifest and prints a summary,
+/// returns the exit code to use if a job failed or the batch was stopped by a signal.
+async fn batch(args: Batch) -> Result<Option<i32>, VidiumError> {
+	let batch = vidium::Batch::load(&args.manifest).map_err(|e| {
+		VidiumError::InvalidArgument(
+			format!("can't load {}: {}", args.manifest.display(), e).into(),
 		)
-		.await;
+	})?;
+
+	let headless = args.browser.headless;
+	let (launch, connect) = args.browser.launch_options();
+
+	let recorder = Recorder::new(RecorderOptions {
+		width: args.width,
+		height: args.height,
+		device_scale_factor: args.device_scale_factor,
+		headless,
+		launch,
+		connect,
+		recording: RecordingOptions {
+			output_template: args.output_template,
+			no_clobber: args.no_clobber,
+			..RecordingOptions::default()
+		},
+		..RecorderOptions::default()
+	});
 
-	let mut listener = page
-		.event_listener::<chromiumoxide::cdp::browser_protocol::page::EventScreencastFrame>()
+	let (reports, signal) = batch
+		.run_or(&recorder, args.concurrency as usize, shutdown_signal())
 		.await?;
 
-	let destination: Locator = args
-		.output
-		.unwrap_or_else(|| {
-			let mut hostname = PathBuf::from(args.url.host_str().unwrap());
-			hostname.set_extension("mp4");
-			hostname
-		})
-		.into();
-	video_rs::init().unwrap();
-
-	let settings = EncoderSettings::for_h264_yuv420p(1600, 1200, true);
-	let mut encoder = Encoder::new(&destination, settings).expect("failed to create encoder");
-
-	let mut prev_duration: Option<Duration> = None;
-	let mut position = Time::zero();
+	let width = reports
+		.iter()
+		.map(|report| report.name.len())
+		.chain([3])
+		.max()
+		.unwrap_or_default();
+
+	println!(
+		"{:<width$}  {:<6}  {:>8}  {:>6}  error",
+		"job", "status", "duration", "frames"
+	);
+
+	let mut failed = 0;
+
+	for report in &reports {
+		let elapsed = format!("{:.1}s", report.elapsed.as_secs_f64());
+
+		match &report.result {
+			Ok(summary) => println!(
+				"{:<width$}  {:<6}  {:>8}  {:>6}",
+				report.name, "ok", elapsed, summary.frames
+			),
+			Err(e) => {
+				failed += 1;
+				println!(
+					"{:<width$}  {:<6}  {:>8}  {:>6}  {}",
+					report.name, "failed", elapsed, "-", e
+				);
+			}
+		}
+	}
 
-	while let Some(item) = listener.next().await {
-		let time = std::time::Instant::now();
-		let buffer = base64::engine::general_purpose::STANDARD
-			.decode(AsRef::<[u8]>::as_ref(&item.data))
-			.unwrap();
+	println!("{} of {} jobs failed", failed, reports.len());
 
-		tracing::info!("{}: {}ms", "base64", time.elapsed().as_millis());
+	if signal.is_some() {
+		return Ok(signal);
+	}
 
-		let time = std::time::Instant::now();
-		let image = image::load_from_memory_with_format(&buffer, image::ImageFormat::Jpeg).unwrap();
-		let image = image.to_rgb8();
+	Ok((failed > 0).then_some(EXIT_BATCH_FAILED))
+}
 
-		tracing::info!("{}: {}ms", "image::load", time.elapsed().as_millis());
+/// Exit code used when some jobs of a batch failed.
+const EXIT_BATCH_FAILED: i32 = 1;
 
-		let time = std::time::Instant::now();
-		let frame = nshare::ToNdarray3::into_ndarray3(image);
-		let frame = frame.permuted_axes([1, 2, 0]);
+/// Exit code used when the recording was interrupted by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
 
-		tracing::info!("{}: {}ms", "ndarray", time.elapsed().as_millis());
+/// Exit code used when the recording was terminated by SIGTERM.
+#[cfg(unix)]
+const EXIT_TERMINATED: i32 = 143;
 
-		println!("frame {:?}", frame.dim());
+/// Resolves with the exit code to use once the process is asked to shut down.
+async fn shutdown_signal() -> i32 {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
 
-		let ts = std::time::Duration::from_nanos(
-			(*item.metadata.timestamp.as_ref().unwrap().inner() * 1000000000.0) as u64,
-		);
+		let mut terminate = match signal(SignalKind::terminate()) {
+			Ok(terminate) => terminate,
+			Err(e) => {
+				tracing::warn!("failed to listen for SIGTERM: {}", e);
+				let _ = tokio::signal::ctrl_c().await;
+				return EXIT_INTERRUPTED;
+			}
+		};
 
-		if let Some(prev) = prev_duration.as_mut() {
-			let delta = ts - *prev;
-			position = position.aligned_with(&delta.into()).add();
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => {
+				tracing::info!("received SIGINT, finalizing the recording");
+				EXIT_INTERRUPTED
+			}
+			_ = terminate.recv() => {
+				tracing::info!("received SIGTERM, finalizing the recording");
+				EXIT_TERMINATED
+			}
 		}
+	}
 
-		prev_duration = Some(ts);
+	#[cfg(not(unix))]
+	{
+		let _ = tokio::signal::ctrl_c().await;
+		tracing::info!("received Ctrl-C, finalizing the recording");
+		EXIT_INTERRUPTED
+	}
+}
 
-		let time = std::time::Instant::now();
-		encoder
-			.encode(&frame, &position)
-			.expect("failed to encode frame");
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_bitrates() {
+		assert_eq!(parse_bitrate("500000"), Ok(500_000));
+		assert_eq!(parse_bitrate("800k"), Ok(800_000));
+		assert_eq!(parse_bitrate("800K"), Ok(800_000));
+		assert_eq!(parse_bitrate("2M"), Ok(2_000_000));
+		assert_eq!(parse_bitrate("2m"), Ok(2_000_000));
+		assert_eq!(parse_bitrate("1.5M"), Ok(1_500_000));
+		assert_eq!(parse_bitrate("0.5k"), Ok(500));
+	}
 
-		tracing::info!("{}: {}ms", "encoder::encode", time.elapsed().as_millis());
+	#[test]
+	fn parses_scales() {
+		assert_eq!(parse_scale("2"), Ok(2.0));
+		assert_eq!(parse_scale("1.5"), Ok(1.5));
 
-		page.execute(
-			chromiumoxide::cdp::browser_protocol::page::ScreencastFrameAckParams::builder()
-				.session_id(item.session_id)
-				.build()
-				.unwrap(),
-		)
-		.await?;
+		for value in ["0", "-1", "inf", "NaN", "", "x"] {
+			assert!(parse_scale(value).is_err(), "{:?} was accepted", value);
+		}
 	}
 
-	encoder.finish().expect("Failed ");
-
-	browser.close().await?;
-	let _ = handle.await;
-	Ok(())
+	#[test]
+	fn rejects_invalid_bitrates() {
+		for value in ["", "M", "k", "2G", "2 M", "abc", "0", "-1M", "inf", "NaN"] {
+			assert!(parse_bitrate(value).is_err(), "{:?} was accepted", value);
+		}
+	}
 }
@@ -0,0 +1,434 @@
+//! Where the video is written.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+use crate::video::extension;
+
+use crate::Error;
+
+/// Placeholders supported by [`OutputTemplate`].
+const PLACEHOLDERS: &[&str] = &["host", "path_slug", "width", "height", "timestamp", "ext"];
+
+/// What the recording is written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+	/// A video file, see [`Codec`](crate::Codec).
+	Video,
+	/// An animated GIF.
+	Gif,
+	/// An animated WebP.
+	Webp,
+	/// A directory with a PNG file per frame and a `frames.json` manifest.
+	PngSeq,
+}
+
+impl Format {
+	/// Format of an output without an explicit one: animations for `.gif` and `.webp`, a video otherwise.
+	pub(crate) fn for_path(path: &Path) -> Format {
+		match extension(path).as_deref() {
+			Some("gif") => Format::Gif,
+			Some("webp") => Format::Webp,
+			_ => Format::Video,
+		}
+	}
+}
+
+/// Path of the video with placeholders filled in when the recording starts.
+///
+/// * `{host}` — host of the page, or the file name for `file://` urls
+/// * `{path_slug}` — path of the page with everything but letters and digits replaced by `-`,
+///   `index` for `/`
+/// * `{width}`, `{height}` — size of the page in css pixels
+/// * `{timestamp}` — UTC time the recording started, like `20230521T153000Z`
+/// * `{ext}` — extension of the video format
+///
+/// ```
+/// # use vidium::OutputTemplate;
+/// let template: OutputTemplate = "{host}/{path_slug}_{width}x{height}.{ext}".parse().unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate(String);
+
+/// Values of the placeholders.
+pub(crate) struct OutputContext<'a> {
+	pub url: &'a Url,
+	pub size: (u32, u32),
+	pub ext: &'a str,
+}
+
+impl Default for OutputTemplate {
+	/// `{host}.{ext}`
+	fn default() -> Self {
+		OutputTemplate("{host}.{ext}".to_owned())
+	}
+}
+
+impl OutputTemplate {
+	/// `{host}`, the directory of an image sequence.
+	pub(crate) fn frames() -> Self {
+		OutputTemplate("{host}".to_owned())
+	}
+}
+
+impl FromStr for OutputTemplate {
+	type Err = Error;
+
+	fn from_str(template: &str) -> Result<Self, Self::Err> {
+		let mut rest = template;
+
+		while let Some(start) = rest.find('{') {
+			let end = rest[start..]
+				.find('}')
+				.ok_or_else(|| format!("unclosed placeholder in {:?}", template))?;
+
+			let name = &rest[start + 1..start + end];
+			if !PLACEHOLDERS.contains(&name) {
+				return Err(format!(
+					"unknown placeholder {{{}}}, expected one of {}",
+					name,
+					PLACEHOLDERS.join(", ")
+				)
+				.into());
+			}
+
+			rest = &rest[start + end + 1..];
+		}
+
+		if template.is_empty() {
+			return Err("the output template is empty".into());
+		}
+
+		Ok(OutputTemplate(template.to_owned()))
+	}
+}
+
+impl fmt::Display for OutputTemplate {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+impl OutputTemplate {
+	pub(crate) fn uses(&self, placeholder: &str) -> bool {
+		self.0.contains(&format!("{{{}}}", placeholder))
+	}
+
+	pub(crate) fn render(&self, context: &OutputContext) -> PathBuf {
+		let mut path = self.0.clone();
+
+		for placeholder in PLACEHOLDERS {
+			let pattern = format!("{{{}}}", placeholder);
+			if !path.contains(&pattern) {
+				continue;
+			}
+
+			let value = match *placeholder {
+				"host" => host(context.url),
+				"path_slug" => path_slug(context.url),
+				"width" => context.size.0.to_string(),
+				"height" => context.size.1.to_string(),
+				"timestamp" => timestamp(SystemTime::now()),
+				"ext" => context.ext.to_owned(),
+				_ => unreachable!("placeholders are checked when the template is parsed"),
+			};
+
+			path = path.replace(&pattern, &value);
+		}
+
+		PathBuf::from(path)
+	}
+}
+
+/// Creates the parent directories of `path` and, for an image sequence, the directory itself.
+///
+/// With `no_clobber` an existing file is kept and `-1`, `-2`, ... is added to the name
+/// until a free one is found. An empty file (or directory) reserves the name, so that
+/// recordings running at the same time don't pick the same one.
+pub(crate) fn prepare(path: &Path, format: Format, no_clobber: bool) -> Result<PathBuf, Error> {
+	if let Some(parent) = path
+		.parent()
+		.filter(|parent| !parent.as_os_str().is_empty())
+	{
+		std::fs::create_dir_all(parent)
+			.map_err(|e| format!("can't create {}: {}", parent.display(), e))?;
+	}
+
+	if !no_clobber {
+		if format == Format::PngSeq {
+			std::fs::create_dir_all(path)
+				.map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+		}
+
+		return Ok(path.to_path_buf());
+	}
+
+	let stem = path
+		.file_stem()
+		.map(|stem| stem.to_string_lossy().into_owned())
+		.unwrap_or_default();
+
+	let mut candidate = path.to_path_buf();
+
+	for suffix in 1.. {
+		let created = match format {
+			Format::PngSeq => std::fs::create_dir(&candidate),
+			_ => OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(&candidate)
+				.map(drop),
+		};
+
+		match created {
+			Ok(()) => break,
+			Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+				let mut name = format!("{}-{}", stem, suffix);
+				if let Some(ext) = path.extension() {
+					name.push('.');
+					name.push_str(&ext.to_string_lossy());
+				}
+				candidate = path.with_file_name(name);
+			}
+			Err(e) => return Err(format!("can't create {}: {}", candidate.display(), e).into()),
+		}
+	}
+
+	if candidate != path {
+		tracing::info!("{} exists, writing {}", path.display(), candidate.display());
+	}
+
+	Ok(candidate)
+}
+
+fn host(url: &Url) -> String {
+	url.host_str()
+		.filter(|host| !host.is_empty())
+		.or_else(|| {
+			url.path_segments()
+				.and_then(|mut segments| segments.next_back())
+				.and_then(|file| file.split('.').next())
+				.filter(|stem| !stem.is_empty())
+		})
+		.unwrap_or("vidium")
+		.to_owned()
+}
+
+fn path_slug(url: &Url) -> String {
+	let mut slug = String::new();
+
+	for c in url.path().chars() {
+		if c.is_ascii_alphanumeric() {
+			slug.push(c.to_ascii_lowercase());
+		} else if !slug.is_empty() && !slug.ends_with('-') {
+			slug.push('-');
+		}
+	}
+
+	let slug = slug.trim_end_matches('-');
+	if slug.is_empty() {
+		"index".to_owned()
+	} else {
+		slug.to_owned()
+	}
+}
+
+/// Formats `time` as `YYYYMMDDTHHMMSSZ`.
+fn timestamp(time: SystemTime) -> String {
+	let seconds = time
+		.duration_since(UNIX_EPOCH)
+		.map(|since| since.as_secs())
+		.unwrap_or_default();
+
+	let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+	// civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+	let z = days + 719468;
+	let era = z / 146097;
+	let doe = z % 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + u64::from(month <= 2);
+
+	format!(
+		"{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+		year,
+		month,
+		day,
+		seconds / 3600,
+		seconds % 3600 / 60,
+		seconds % 60
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+
+	fn url(url: &str) -> Url {
+		url.parse().unwrap()
+	}
+
+	/// An empty directory for the test.
+	fn directory(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("vidium-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	#[test]
+	fn parses_templates() {
+		let template: OutputTemplate = "{host}/{path_slug}_{width}x{height}.{ext}".parse().unwrap();
+		assert!(template.uses("width"));
+		assert!(!template.uses("timestamp"));
+
+		assert!("video.mp4".parse::<OutputTemplate>().is_ok());
+	}
+
+	#[test]
+	fn rejects_invalid_templates() {
+		assert!("".parse::<OutputTemplate>().is_err());
+		assert!("{host".parse::<OutputTemplate>().is_err());
+		assert!("{host}/{path".parse::<OutputTemplate>().is_err());
+		assert!("{hostname}.mp4".parse::<OutputTemplate>().is_err());
+		assert!("{}.mp4".parse::<OutputTemplate>().is_err());
+	}
+
+	#[test]
+	fn renders_templates() {
+		let template: OutputTemplate = "{host}/{path_slug}_{width}x{height}.{ext}".parse().unwrap();
+		let path = template.render(&OutputContext {
+			url: &url("https://example.com/docs/Getting_Started?page=2"),
+			size: (1280, 720),
+			ext: "webm",
+		});
+
+		assert_eq!(
+			path,
+			PathBuf::from("example.com/docs-getting-started_1280x720.webm")
+		);
+	}
+
+	#[test]
+	fn hosts() {
+		assert_eq!(host(&url("https://example.com/pricing")), "example.com");
+		assert_eq!(host(&url("http://localhost:8080/")), "localhost");
+		assert_eq!(host(&url("file:///home/demo/page.html")), "page");
+		assert_eq!(host(&url("file:///")), "vidium");
+		assert_eq!(host(&url("about:blank")), "vidium");
+	}
+
+	#[test]
+	fn path_slugs() {
+		assert_eq!(path_slug(&url("https://example.com")), "index");
+		assert_eq!(path_slug(&url("https://example.com/")), "index");
+		assert_eq!(
+			path_slug(&url("https://example.com/Blog/2023/hello-world/")),
+			"blog-2023-hello-world"
+		);
+		assert_eq!(path_slug(&url("https://example.com/a%20b//c")), "a-20b-c");
+	}
+
+	#[test]
+	fn timestamps() {
+		assert_eq!(timestamp(UNIX_EPOCH), "19700101T000000Z");
+		assert_eq!(
+			timestamp(UNIX_EPOCH + Duration::from_secs(1_684_683_000)),
+			"20230521T153000Z"
+		);
+		// leap day
+		assert_eq!(
+			timestamp(UNIX_EPOCH + Duration::from_secs(951_868_799)),
+			"20000229T235959Z"
+		);
+		assert_eq!(
+			timestamp(UNIX_EPOCH + Duration::from_secs(951_868_800)),
+			"20000301T000000Z"
+		);
+		// end of the year
+		assert_eq!(
+			timestamp(UNIX_EPOCH + Duration::from_secs(1_704_067_199)),
+			"20231231T235959Z"
+		);
+	}
+
+	#[test]
+	fn formats() {
+		assert_eq!(Format::for_path(Path::new("demo.GIF")), Format::Gif);
+		assert_eq!(Format::for_path(Path::new("demo.webp")), Format::Webp);
+		assert_eq!(Format::for_path(Path::new("demo.mp4")), Format::Video);
+		assert_eq!(Format::for_path(Path::new("demo")), Format::Video);
+	}
+
+	#[test]
+	fn prepare_creates_directories() {
+		let dir = directory("prepare");
+
+		let path = dir.join("a/b/video.mp4");
+		assert_eq!(prepare(&path, Format::Video, false).unwrap(), path);
+		assert!(dir.join("a/b").is_dir());
+		assert!(!path.exists());
+
+		let frames = dir.join("frames");
+		assert_eq!(prepare(&frames, Format::PngSeq, false).unwrap(), frames);
+		assert!(frames.is_dir());
+	}
+
+	#[test]
+	fn prepare_overwrites_without_no_clobber() {
+		let dir = directory("overwrite");
+
+		let path = dir.join("video.mp4");
+		std::fs::write(&path, "old").unwrap();
+		assert_eq!(prepare(&path, Format::Video, false).unwrap(), path);
+	}
+
+	#[test]
+	fn no_clobber_adds_suffixes() {
+		let dir = directory("no-clobber");
+
+		let path = dir.join("video.mp4");
+		std::fs::write(&path, "old").unwrap();
+
+		assert_eq!(
+			prepare(&path, Format::Video, true).unwrap(),
+			dir.join("video-1.mp4")
+		);
+		// the name is reserved, the next recording gets another one
+		assert_eq!(
+			prepare(&path, Format::Video, true).unwrap(),
+			dir.join("video-2.mp4")
+		);
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+
+		let fresh = dir.join("fresh.mp4");
+		assert_eq!(prepare(&fresh, Format::Video, true).unwrap(), fresh);
+		assert!(fresh.exists());
+	}
+
+	#[test]
+	fn no_clobber_frames() {
+		let dir = directory("no-clobber-frames");
+
+		let frames = dir.join("frames");
+		assert_eq!(prepare(&frames, Format::PngSeq, true).unwrap(), frames);
+		assert_eq!(
+			prepare(&frames, Format::PngSeq, true).unwrap(),
+			dir.join("frames-1")
+		);
+		assert!(dir.join("frames-1").is_dir());
+	}
+}
@@ -0,0 +1,640 @@
+//! Frames go through three stages connected by bounded channels:
+//!
+//! 1. the recording task receives a frame, acknowledges it right away so that the browser
+//!    can send the next one, and pushes it into the pipeline
+//! 2. a pool of decoder threads turns the base64 encoded images into pixels
+//! 3. a dedicated encoder thread puts the frames back in order and encodes them
+//!
+//! When a stage falls behind, its queue fills up and the previous stage waits.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+
+use base64::Engine;
+use chromiumoxide::cdp::browser_protocol::page::ScreencastFrameMetadata;
+use chromiumoxide::Binary;
+use image::RgbImage;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use crate::capture::{CaptureFormat, Frame};
+use crate::encoder::FrameEncoder;
+use crate::recording::Marker;
+use crate::timing::TimingCorrections;
+use crate::{Error, VidiumError};
+
+/// How many messages each queue holds before the previous stage has to wait.
+pub(crate) const QUEUE_CAPACITY: usize = 16;
+
+/// Upper bound for the number of decoder threads.
+const MAX_DECODERS: usize = 4;
+
+/// What to do with a new frame when the decode queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BacklogPolicy {
+	/// Wait for room in the queue, the browser slows down to the speed of the encoder.
+	#[default]
+	Block,
+	/// Drop the oldest queued frame to make room for the new one.
+	DropOldest,
+	/// Drop the new frame.
+	DropNewest,
+}
+
+/// A message for the pipeline, processed in the order it was pushed.
+pub(crate) enum Input {
+	/// A frame and the moment it was received.
+	Frame(Frame, Instant),
+	Pause(Instant),
+	Resume(Instant),
+	/// A marker and when the page created it.
+	Marker(String, Instant),
+}
+
+/// A frame ready to be encoded.
+pub(crate) struct Decoded {
+	pub(crate) image: RgbImage,
+	/// Timing of a screencast frame, `None` for frames captured with the virtual clock.
+	pub(crate) metadata: Option<ScreencastFrameMetadata>,
+	pub(crate) received_at: Instant,
+}
+
+enum Output {
+	Frame(Decoded),
+	Pause(Instant),
+	Resume(Instant),
+	Marker(String, Instant),
+	Failed(Error),
+}
+
+/// Peak number of messages waiting in each queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueDepths {
+	/// Frames received from the page and waiting to be decoded.
+	pub decode: usize,
+	/// Decoded frames waiting to be encoded.
+	pub encode: usize,
+}
+
+#[derive(Default)]
+struct Stats {
+	decode_queue: AtomicUsize,
+	encode_queue: AtomicUsize,
+	dropped: AtomicU64,
+	/// Notified once the encoder reaches the maximum number of frames.
+	full: Notify,
+}
+
+/// Number of encoded frames, markers and timestamp corrections.
+type EncoderOutput = (u64, Vec<Marker>, TimingCorrections);
+
+/// What the encoder thread produced.
+pub(crate) struct Encoded {
+	pub(crate) frames: u64,
+	pub(crate) markers: Vec<Marker>,
+	pub(crate) queues: QueueDepths,
+	pub(crate) dropped: u64,
+	pub(crate) timing: TimingCorrections,
+}
+
+/// Messages waiting to be decoded.
+///
+/// Unlike a channel, it lets the oldest frame be dropped when the queue is full. Commands
+/// are never dropped and don't count towards the capacity.
+struct Backlog {
+	state: Mutex<BacklogState>,
+	/// Wakes up the decoders when there is a new message or the backlog is closed.
+	available: Condvar,
+	/// Wakes up the recording task when there is room for a new frame or the backlog is closed.
+	room: Notify,
+	capacity: usize,
+	policy: BacklogPolicy,
+}
+
+#[derive(Default)]
+struct BacklogState {
+	messages: VecDeque<Input>,
+	/// Number of frames among the messages.
+	frames: usize,
+	/// Sequence number of the next taken message, used to restore the order after decoding.
+	seq: u64,
+	closed: bool,
+}
+
+/// Result of pushing a frame into the backlog.
+enum Pushed {
+	Queued,
+	/// The queue was full and this frame was dropped, it may be the pushed one or an older one.
+	Dropped(Frame),
+	/// Room is needed and the policy is to wait for it.
+	Full(Input),
+	Closed,
+}
+
+impl Backlog {
+	fn new(capacity: usize, policy: BacklogPolicy) -> Self {
+		Backlog {
+			state: Mutex::new(BacklogState::default()),
+			available: Condvar::new(),
+			room: Notify::new(),
+			capacity: capacity.max(1),
+			policy,
+		}
+	}
+
+	fn lock(&self) -> MutexGuard<'_, BacklogState> {
+		// decoders don't panic while holding the lock, the state is always consistent
+		self.state.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn push(&self, mut input: Input) -> Pushed {
+		let mut state = self.lock();
+
+		if state.closed {
+			return Pushed::Closed;
+		}
+
+		let is_frame = matches!(input, Input::Frame(..));
+		let mut dropped = None;
+
+		if is_frame && state.frames >= self.capacity {
+			match (self.policy, input) {
+				(BacklogPolicy::Block, input) => return Pushed::Full(input),
+				(BacklogPolicy::DropNewest, Input::Frame(frame, _)) => {
+					return Pushed::Dropped(frame)
+				}
+				(_, rejected) => {
+					input = rejected;
+
+					let oldest = state
+						.messages
+						.iter()
+						.position(|message| matches!(message, Input::Frame(..)));
+
+					if let Some(Input::Frame(frame, _)) =
+						oldest.and_then(|index| state.messages.remove(index))
+					{
+						state.frames -= 1;
+						dropped = Some(frame);
+					}
+				}
+			}
+		}
+
+		if is_frame {
+			state.frames += 1;
+		}
+
+		state.messages.push_back(input);
+		self.available.notify_one();
+
+		match dropped {
+			Some(frame) => Pushed::Dropped(frame),
+			None => Pushed::Queued,
+		}
+	}
+
+	/// Blocks until there is a message, returns `None` once the backlog is closed and empty.
+	fn take(&self) -> Option<(u64, Input)> {
+		let mut state = self.lock();
+
+		loop {
+			if let Some(input) = state.messages.pop_front() {
+				if matches!(input, Input::Frame(..)) {
+					state.frames -= 1;
+					self.room.notify_one();
+				}
+
+				let seq = state.seq;
+				state.seq += 1;
+
+				return Some((seq, input));
+			}
+
+			if state.closed {
+				return None;
+			}
+
+			state = self
+				.available
+				.wait(state)
+				.unwrap_or_else(|e| e.into_inner());
+		}
+	}
+
+	fn frames(&self) -> usize {
+		self.lock().frames
+	}
+
+	/// No new messages are accepted, the decoders stop once the queued ones are taken.
+	fn close(&self) {
+		self.lock().closed = true;
+		self.available.notify_all();
+		self.room.notify_one();
+	}
+}
+
+pub(crate) struct Pipeline {
+	backlog: Arc<Backlog>,
+	stats: Arc<Stats>,
+	done: oneshot::Receiver<Result<EncoderOutput, VidiumError>>,
+}
+
+impl Pipeline {
+	pub(crate) fn start(
+		encoder: FrameEncoder,
+		format: CaptureFormat,
+		policy: BacklogPolicy,
+		max_queued_frames: usize,
+	) -> Result<Self, Error> {
+		let stats = Arc::new(Stats::default());
+
+		let backlog = Arc::new(Backlog::new(max_queued_frames, policy));
+
+		let (decoded, mut to_encode) = mpsc::channel(QUEUE_CAPACITY);
+
+		let decoders = std::thread::available_parallelism()
+			.map(|n| n.get())
+			.unwrap_or(1)
+			.clamp(1, MAX_DECODERS);
+
+		tracing::debug!("starting {} decoder threads", decoders);
+
+		for index in 0..decoders {
+			let backlog = backlog.clone();
+			let decoded = decoded.clone();
+			let stats = stats.clone();
+
+			std::thread::Builder::new()
+				.name(format!("vidium-decoder-{}", index))
+				.spawn(move || decode_frames(&backlog, decoded, format, &stats))?;
+		}
+
+		let (finished, done) = oneshot::channel();
+		let encoder_backlog = backlog.clone();
+		let encoder_stats = stats.clone();
+
+		std::thread::Builder::new()
+			.name("vidium-encoder".to_owned())
+			.spawn(move || {
+				let result = encode_frames(encoder, &mut to_encode, &encoder_stats);
+				// nothing can be encoded anymore, stop accepting frames
+				encoder_backlog.close();
+				let _ = finished.send(result);
+			})?;
+
+		Ok(Pipeline {
+			backlog,
+			stats,
+			done,
+		})
+	}
+
+	/// Queues a message according to the backlog policy, returns `false` if the encoder has
+	/// stopped because of an error.
+	pub(crate) async fn push(&self, mut input: Input) -> bool {
+		loop {
+			match self.backlog.push(input) {
+				Pushed::Queued => break,
+				Pushed::Dropped(frame) => {
+					let dropped = self.stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+					tracing::warn!(
+						"decode queue is full, dropped a frame with timestamp {:?} ({} dropped so far)",
+						frame.timestamp(),
+						dropped
+					);
+					break;
+				}
+				Pushed::Full(rejected) => {
+					input = rejected;
+					self.backlog.room.notified().await;
+				}
+				Pushed::Closed => return false,
+			}
+		}
+
+		let depth = self.backlog.frames();
+		self.stats.decode_queue.fetch_max(depth, Ordering::Relaxed);
+
+		tracing::debug!("decode queue: {}/{}", depth, self.backlog.capacity);
+
+		true
+	}
+
+	/// Resolves once the maximum number of frames is encoded.
+	pub(crate) async fn full(&self) {
+		self.stats.full.notified().await
+	}
+
+	/// Waits until every pushed frame is encoded and the video is finalized.
+	pub(crate) async fn finish(mut self) -> Result<Encoded, VidiumError> {
+		self.backlog.close();
+
+		let (frames, markers, timing) = (&mut self.done)
+			.await
+			.map_err(|_| VidiumError::Encode("the encoder thread has panicked".into()))??;
+
+		let queues = QueueDepths {
+			decode: self.stats.decode_queue.load(Ordering::Relaxed),
+			encode: self.stats.encode_queue.load(Ordering::Relaxed),
+		};
+
+		tracing::info!(
+			"peak queue depth: decode {}/{}, encode {}/{}",
+			queues.decode,
+			self.backlog.capacity,
+			queues.encode,
+			QUEUE_CAPACITY
+		);
+
+		if timing.total() > 0 {
+			tracing::info!("corrected screencast timestamps: {:?}", timing);
+		}
+
+		let dropped = self.stats.dropped.load(Ordering::Relaxed);
+
+		if dropped > 0 {
+			tracing::warn!(
+				"{} frames were dropped because the encoder fell behind",
+				dropped
+			);
+		}
+
+		Ok(Encoded {
+			frames,
+			markers,
+			queues,
+			dropped,
+			timing,
+		})
+	}
+}
+
+impl Drop for Pipeline {
+	fn drop(&mut self) {
+		// lets the threads exit if the recording is abandoned
+		self.backlog.close();
+	}
+}
+
+/// Runs on a decoder thread until the backlog is closed or the encoder goes away.
+fn decode_frames(
+	backlog: &Backlog,
+	decoded: mpsc::Sender<(u64, Output)>,
+	format: CaptureFormat,
+	stats: &Stats,
+) {
+	while let Some((seq, input)) = backlog.take() {
+		let output = match input {
+			Input::Frame(frame, received_at) => match decode_frame(frame, format, received_at) {
+				Ok(decoded) => Output::Frame(decoded),
+				Err(e) => Output::Failed(e),
+			},
+			Input::Pause(at) => Output::Pause(at),
+			Input::Resume(at) => Output::Resume(at),
+			Input::Marker(name, at) => Output::Marker(name, at),
+		};
+
+		if decoded.blocking_send((seq, output)).is_err() {
+			return;
+		}
+
+		let depth = QUEUE_CAPACITY - decoded.capacity();
+		stats.encode_queue.fetch_max(depth, Ordering::Relaxed);
+	}
+}
+
+fn decode_frame(
+	frame: Frame,
+	format: CaptureFormat,
+	received_at: Instant,
+) -> Result<Decoded, Error> {
+	Ok(match frame {
+		Frame::Screencast(item) => Decoded {
+			image: decode(&item.data, format)?,
+			metadata: Some(item.metadata.clone()),
+			received_at,
+		},
+		Frame::Captured(data) => Decoded {
+			image: decode(&data, format)?,
+			metadata: None,
+			received_at,
+		},
+	})
+}
+
+/// Runs on the encoder thread, messages are encoded in the order they were pushed.
+fn encode_frames(
+	mut encoder: FrameEncoder,
+	to_encode: &mut mpsc::Receiver<(u64, Output)>,
+	stats: &Stats,
+) -> Result<EncoderOutput, VidiumError> {
+	let mut markers = Vec::new();
+	let mut reordered = BTreeMap::new();
+	let mut next = 0;
+
+	while let Some((seq, output)) = to_encode.blocking_recv() {
+		reordered.insert(seq, output);
+
+		while let Some(output) = reordered.remove(&next) {
+			next += 1;
+
+			match output {
+				Output::Frame(decoded) => {
+					if encoder.is_full() {
+						continue;
+					}
+
+					encoder.encode(decoded).map_err(VidiumError::Encode)?;
+
+					if encoder.is_full() {
+						stats.full.notify_one();
+					}
+				}
+				Output::Pause(at) => encoder.pause(at),
+				Output::Resume(at) => encoder.resume(at),
+				Output::Marker(name, at) => {
+					let position = encoder.position_at(at);
+					tracing::info!("marker {:?} at {:?}", name, position);
+					markers.push(Marker { name, position });
+				}
+				Output::Failed(e) => return Err(VidiumError::Decode(e)),
+			}
+		}
+	}
+
+	let timing = encoder.corrections();
+	let frames = encoder.finish().map_err(VidiumError::Encode)?;
+
+	Ok((frames, markers, timing))
+}
+
+fn decode(data: &Binary, format: CaptureFormat) -> Result<RgbImage, Error> {
+	let time = std::time::Instant::now();
+	let buffer = base64::engine::general_purpose::STANDARD.decode(AsRef::<[u8]>::as_ref(data))?;
+
+	tracing::info!("{}: {}ms", "base64", time.elapsed().as_millis());
+
+	let time = std::time::Instant::now();
+	let image = image::load_from_memory_with_format(&buffer, format.image_format())?;
+	let image = image.to_rgb8();
+
+	tracing::info!("{}: {}ms", "image::load", time.elapsed().as_millis());
+
+	Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn frame(id: &str) -> Input {
+		Input::Frame(Frame::Captured(Binary::from(id.to_owned())), Instant::now())
+	}
+
+	/// Id of a frame, or the name of a command.
+	fn id(input: &Input) -> String {
+		match input {
+			Input::Frame(Frame::Captured(data), _) => AsRef::<str>::as_ref(data).to_owned(),
+			Input::Frame(Frame::Screencast(_), _) => "screencast".to_owned(),
+			Input::Pause(_) => "pause".to_owned(),
+			Input::Resume(_) => "resume".to_owned(),
+			Input::Marker(name, _) => format!("marker {}", name),
+		}
+	}
+
+	fn dropped(pushed: Pushed) -> String {
+		match pushed {
+			Pushed::Dropped(frame) => id(&Input::Frame(frame, Instant::now())),
+			_ => panic!("no frame was dropped"),
+		}
+	}
+
+	/// Takes everything from a closed backlog.
+	fn drain(backlog: &Backlog) -> Vec<String> {
+		backlog.close();
+		std::iter::from_fn(|| backlog.take())
+			.map(|(_, input)| id(&input))
+			.collect()
+	}
+
+	#[test]
+	fn queues_until_full() {
+		let backlog = Backlog::new(2, BacklogPolicy::Block);
+
+		assert!(matches!(backlog.push(frame("1")), Pushed::Queued));
+		assert!(matches!(backlog.push(frame("2")), Pushed::Queued));
+		assert_eq!(backlog.frames(), 2);
+
+		match backlog.push(frame("3")) {
+			Pushed::Full(input) => assert_eq!(id(&input), "3"),
+			_ => panic!("the frame was queued into a full backlog"),
+		}
+		assert_eq!(backlog.frames(), 2);
+
+		assert_eq!(drain(&backlog), ["1", "2"]);
+	}
+
+	#[test]
+	fn drops_oldest() {
+		let backlog = Backlog::new(2, BacklogPolicy::DropOldest);
+
+		backlog.push(frame("1"));
+		backlog.push(frame("2"));
+		assert_eq!(dropped(backlog.push(frame("3"))), "1");
+		assert_eq!(backlog.frames(), 2);
+
+		assert_eq!(drain(&backlog), ["2", "3"]);
+	}
+
+	#[test]
+	fn drops_newest() {
+		let backlog = Backlog::new(2, BacklogPolicy::DropNewest);
+
+		backlog.push(frame("1"));
+		backlog.push(frame("2"));
+		assert_eq!(dropped(backlog.push(frame("3"))), "3");
+		assert_eq!(backlog.frames(), 2);
+
+		assert_eq!(drain(&backlog), ["1", "2"]);
+	}
+
+	#[test]
+	fn commands_bypass_the_capacity() {
+		let backlog = Backlog::new(1, BacklogPolicy::Block);
+
+		backlog.push(frame("1"));
+		assert!(matches!(
+			backlog.push(Input::Marker("start".to_owned(), Instant::now())),
+			Pushed::Queued
+		));
+		assert!(matches!(
+			backlog.push(Input::Pause(Instant::now())),
+			Pushed::Queued
+		));
+		assert!(matches!(
+			backlog.push(Input::Resume(Instant::now())),
+			Pushed::Queued
+		));
+		assert_eq!(backlog.frames(), 1);
+		assert!(matches!(backlog.push(frame("2")), Pushed::Full(_)));
+
+		assert_eq!(drain(&backlog), ["1", "marker start", "pause", "resume"]);
+	}
+
+	#[test]
+	fn drops_frames_but_not_commands() {
+		let backlog = Backlog::new(1, BacklogPolicy::DropOldest);
+
+		backlog.push(Input::Marker("start".to_owned(), Instant::now()));
+		backlog.push(frame("1"));
+		backlog.push(Input::Pause(Instant::now()));
+		assert_eq!(dropped(backlog.push(frame("2"))), "1");
+
+		assert_eq!(drain(&backlog), ["marker start", "pause", "2"]);
+	}
+
+	#[test]
+	fn takes_in_order() {
+		let backlog = Backlog::new(4, BacklogPolicy::Block);
+
+		backlog.push(frame("1"));
+		backlog.push(Input::Marker("start".to_owned(), Instant::now()));
+		backlog.push(frame("2"));
+
+		let (seq, input) = backlog.take().unwrap();
+		assert_eq!((seq, id(&input).as_str()), (0, "1"));
+		assert_eq!(backlog.frames(), 1);
+
+		let (seq, input) = backlog.take().unwrap();
+		assert_eq!((seq, id(&input).as_str()), (1, "marker start"));
+		assert_eq!(backlog.frames(), 1);
+
+		let (seq, input) = backlog.take().unwrap();
+		assert_eq!((seq, id(&input).as_str()), (2, "2"));
+		assert_eq!(backlog.frames(), 0);
+	}
+
+	#[test]
+	fn closed() {
+		let backlog = Backlog::new(2, BacklogPolicy::Block);
+
+		backlog.push(frame("1"));
+		backlog.close();
+
+		assert!(matches!(backlog.push(frame("2")), Pushed::Closed));
+		assert!(matches!(
+			backlog.push(Input::Marker("late".to_owned(), Instant::now())),
+			Pushed::Closed
+		));
+
+		// the queued messages are still decoded
+		assert_eq!(
+			backlog.take().map(|(_, input)| id(&input)).as_deref(),
+			Some("1")
+		);
+		assert!(backlog.take().is_none());
+	}
+}
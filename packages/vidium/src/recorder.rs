@@ -0,0 +1,377 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chromiumoxide::browser::{Browser, BrowserConfig};
+use chromiumoxide::handler::viewport::Viewport;
+use chromiumoxide::handler::HandlerConfig;
+use chromiumoxide::Page;
+use futures::StreamExt;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::recording::{record_page, RecordingHandle, RecordingOptions};
+use crate::VidiumError;
+
+/// How long to wait for an existing tab to be attached.
+const TARGET_ATTACH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to check whether the tab is attached.
+const TARGET_ATTACH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Options used to launch the browser and encode the recording.
+#[derive(Debug, Clone)]
+pub struct RecorderOptions {
+	/// Width of the browser window.
+	pub width: u32,
+	/// Height of the browser window.
+	pub height: u32,
+	/// Device pixel ratio of the page, the default of the browser when not set.
+	///
+	/// Also defines the size of the video: `width * device_scale_factor` by
+	/// `height * device_scale_factor`.
+	pub device_scale_factor: Option<f64>,
+	/// Run the browser without UI.
+	pub headless: bool,
+	/// Open a blank page and start capturing before navigating to the url, so that
+	/// the page load is recorded too.
+	pub record_from_start: bool,
+	/// How to launch the browser, ignored when connecting to a running one.
+	pub launch: LaunchOptions,
+	/// Connect to a running browser instead of launching one: a WebSocket debugger url
+	/// (`ws://127.0.0.1:9222/devtools/browser/<id>`) or an http endpoint (`http://127.0.0.1:9222`).
+	///
+	/// A browser vidium connected to is left running when the recording is finished.
+	pub connect: Option<String>,
+	/// Capture and encoding options.
+	pub recording: RecordingOptions,
+}
+
+impl Default for RecorderOptions {
+	fn default() -> Self {
+		RecorderOptions {
+			width: 800,
+			height: 600,
+			device_scale_factor: None,
+			headless: false,
+			record_from_start: false,
+			launch: LaunchOptions::default(),
+			connect: None,
+			recording: RecordingOptions::default(),
+		}
+	}
+}
+
+/// Options used to launch the browser.
+#[derive(Debug, Clone, Default)]
+pub struct LaunchOptions {
+	/// Browser executable, detected automatically when not set.
+	pub chrome_path: Option<PathBuf>,
+	/// Extra command line arguments of the browser, e.g. `--lang=de`.
+	pub chrome_args: Vec<String>,
+	/// Profile directory, keeps cookies and logins between recordings.
+	///
+	/// A temporary profile is used when not set.
+	pub user_data_dir: Option<PathBuf>,
+	/// Disable the browser sandbox, required when running as root (e.g. in containers).
+	pub no_sandbox: bool,
+	/// Open pages in an incognito context.
+	pub incognito: bool,
+	/// Unpacked extensions to load. Extensions don't work in headless mode.
+	pub extensions: Vec<PathBuf>,
+}
+
+/// Launches or connects to a browser and records a page into a video file.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+	options: RecorderOptions,
+}
+
+impl Recorder {
+	pub fn new(options: RecorderOptions) -> Self {
+		Recorder { options }
+	}
+
+	pub fn options(&self) -> &RecorderOptions {
+		&self.options
+	}
+
+	/// Opens `url` in a new tab and starts recording it.
+	///
+	/// The browser is launched, or connected to when [`RecorderOptions::connect`] is set.
+	/// The recording runs in the background until [`RecordingHandle::stop`] is called
+	/// or the page goes away.
+	pub async fn start(&self, url: Url) -> Result<RecordingHandle, VidiumError> {
+		let browser = self.browser().await?;
+
+		let (open, navigate_to) = if self.options.record_from_start {
+			("about:blank", Some(url.clone()))
+		} else {
+			(url.as_str(), None)
+		};
+
+		let page = async {
+			let page = browser.browser.new_page(open).await?;
+			page.wait_for_navigation().await?;
+			Ok::<_, chromiumoxide::error::CdpError>(page)
+		}
+		.await;
+
+		match page {
+			Ok(page) => self.record(browser, page, navigate_to, true).await,
+			Err(e) => {
+				let _ = browser.close().await;
+				Err(VidiumError::Navigation(e.into()))
+			}
+		}
+	}
+
+	/// Records a tab that is already open in the browser set in [`RecorderOptions::connect`].
+	///
+	/// The first page whose url or title contains `pattern` is recorded.
+	pub async fn start_target(&self, pattern: &str) -> Result<RecordingHandle, VidiumError> {
+		if self.options.connect.is_none() {
+			return Err(VidiumError::InvalidArgument(
+				"an existing tab can only be recorded in a browser vidium connects to".into(),
+			));
+		}
+
+		let mut browser = self.browser().await?;
+
+		match find_target(&mut browser.browser, pattern).await {
+			Ok(page) => self.record(browser, page, None, false).await,
+			Err(e) => {
+				let _ = browser.close().await;
+				Err(e)
+			}
+		}
+	}
+
+	/// Launches a new browser or connects to a running one.
+	pub(crate) async fn browser(&self) -> Result<BrowserSession, VidiumError> {
+		let options = &self.options;
+
+		if let Some(scale) = options.device_scale_factor {
+			check_scale(scale)?;
+		}
+
+		let viewport = Viewport {
+			width: options.width,
+			height: options.height,
+			device_scale_factor: options.device_scale_factor,
+			..Viewport::default()
+		};
+
+		let (browser, mut handler) = match &options.connect {
+			Some(url) => {
+				let config = HandlerConfig {
+					viewport: Some(viewport),
+					..HandlerConfig::default()
+				};
+
+				Browser::connect_with_config(url.as_str(), config)
+					.await
+					.map_err(|e| {
+						VidiumError::Browser(format!("can't connect to {}: {}", url, e).into())
+					})?
+			}
+			None => {
+				// create a `Browser` that spawns a `chromium` process running with UI (`with_head()`, headless is default)
+				// and the handler that drives the websocket etc.
+				let mut builder = BrowserConfig::builder()
+					.window_size(options.width, options.height)
+					.viewport(viewport);
+
+				if !options.headless {
+					builder = builder.with_head()
+				}
+
+				let launch = &options.launch;
+
+				if let Some(path) = &launch.chrome_path {
+					builder = builder.chrome_executable(path);
+				}
+
+				if let Some(dir) = &launch.user_data_dir {
+					builder = builder.user_data_dir(dir);
+				}
+
+				if launch.no_sandbox {
+					builder = builder.no_sandbox();
+				}
+
+				if launch.incognito {
+					builder = builder.incognito();
+				}
+
+				if !launch.extensions.is_empty() {
+					let dirs: Vec<_> = launch
+						.extensions
+						.iter()
+						.map(|dir| dir.to_string_lossy())
+						.collect();
+					let dirs = dirs.join(",");
+
+					// the default arguments contain `--disable-extensions`, which wins over
+					// `--load-extension` unless the extensions are exempted; Chrome also only
+					// reads the last `--load-extension`, so all of them go into one
+					builder = builder
+						.arg(format!("--disable-extensions-except={}", dirs))
+						.arg(format!("--load-extension={}", dirs));
+				}
+
+				for arg in &launch.chrome_args {
+					builder = builder.arg(arg);
+				}
+
+				let config = builder
+					.build()
+					.map_err(|e| VidiumError::Browser(e.into()))?;
+
+				Browser::launch(config)
+					.await
+					.map_err(|e| VidiumError::Browser(e.into()))?
+			}
+		};
+
+		// spawn a new task that continuously polls the handler
+		let handler = tokio::task::spawn(async move {
+			while let Some(h) = handler.next().await {
+				if h.is_err() {
+					break;
+				}
+			}
+		});
+
+		Ok(BrowserSession {
+			browser,
+			handler,
+			launched: options.connect.is_none(),
+		})
+	}
+
+	/// `opened` is set for a tab opened by vidium, it is closed once the recording is finished.
+	async fn record(
+		&self,
+		browser: BrowserSession,
+		page: Page,
+		navigate_to: Option<Url>,
+		opened: bool,
+	) -> Result<RecordingHandle, VidiumError> {
+		let mut recording = self.options.recording.clone();
+		recording.navigate_to = navigate_to.or(recording.navigate_to);
+
+		let started = async {
+			if let (None, Some(scale)) = (recording.size, self.options.device_scale_factor) {
+				recording.size = Some(scaled_size(self.options.width, self.options.height, scale)?);
+			}
+
+			record_page(&page, &recording).await
+		};
+
+		match started.await {
+			Ok(recording) => {
+				let recording = recording.with_browser(browser);
+				Ok(if opened {
+					recording.with_page(page)
+				} else {
+					recording
+				})
+			}
+			Err(e) => {
+				if opened {
+					let _ = page.close().await;
+				}
+				let _ = browser.close().await;
+				Err(e)
+			}
+		}
+	}
+}
+
+/// A browser used by the [`Recorder`] and the task driving its connection.
+pub(crate) struct BrowserSession {
+	browser: Browser,
+	handler: JoinHandle<()>,
+	/// Whether the browser was launched by vidium, only then it is closed at the end.
+	launched: bool,
+}
+
+impl BrowserSession {
+	pub(crate) fn browser(&self) -> &Browser {
+		&self.browser
+	}
+
+	pub(crate) async fn close(self) -> Result<(), VidiumError> {
+		let BrowserSession {
+			mut browser,
+			handler,
+			launched,
+		} = self;
+
+		if !launched {
+			// just disconnect, the browser keeps running
+			handler.abort();
+			return Ok(());
+		}
+
+		let closed = browser.close().await;
+		let _ = handler.await;
+
+		closed.map_err(|e| VidiumError::Browser(e.into()))?;
+		Ok(())
+	}
+}
+
+/// Size of the captured frames of a `width`x`height` page with the given device pixel ratio.
+pub(crate) fn scaled_size(width: u32, height: u32, scale: f64) -> Result<(u32, u32), VidiumError> {
+	check_scale(scale)?;
+
+	Ok((
+		(f64::from(width) * scale).round() as u32,
+		(f64::from(height) * scale).round() as u32,
+	))
+}
+
+/// Fails unless the device pixel ratio is a finite number above 0.
+fn check_scale(scale: f64) -> Result<(), VidiumError> {
+	if scale.is_finite() && scale > 0.0 {
+		Ok(())
+	} else {
+		Err(VidiumError::InvalidArgument(
+			format!("the device scale factor must be above 0, got {}", scale).into(),
+		))
+	}
+}
+
+/// Finds an open page whose url or title contains `pattern`.
+async fn find_target(browser: &mut Browser, pattern: &str) -> Result<Page, VidiumError> {
+	let targets = browser
+		.fetch_targets()
+		.await
+		.map_err(|e| VidiumError::Browser(e.into()))?;
+
+	let target = targets
+		.into_iter()
+		.filter(|target| target.r#type == "page")
+		.find(|target| target.url.contains(pattern) || target.title.contains(pattern))
+		.ok_or_else(|| {
+			VidiumError::Navigation(format!("no open page matches {:?}", pattern).into())
+		})?;
+
+	tracing::info!("recording {:?} ({})", target.title, target.url);
+
+	// the fetched targets are attached in the background
+	let deadline = tokio::time::Instant::now() + TARGET_ATTACH_TIMEOUT;
+
+	loop {
+		match browser.get_page(target.target_id.clone()).await {
+			Ok(page) => return Ok(page),
+			Err(e) if tokio::time::Instant::now() >= deadline => {
+				return Err(VidiumError::Navigation(
+					format!("can't attach to {}: {}", target.url, e).into(),
+				));
+			}
+			Err(_) => tokio::time::sleep(TARGET_ATTACH_INTERVAL).await,
+		}
+	}
+}
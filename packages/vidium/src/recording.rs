@@ -0,0 +1,541 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chromiumoxide::Page;
+use tokio::sync::oneshot;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::Instant;
+use url::Url;
+
+use crate::animation::AnimationOptions;
+use crate::capture::{CaptureOptions, Source};
+use crate::encoder::{FrameEncoder, Target};
+use crate::output::{self, Format, OutputContext, OutputTemplate};
+use crate::pipeline::{BacklogPolicy, Input, Pipeline, QueueDepths, QUEUE_CAPACITY};
+use crate::recorder::BrowserSession;
+use crate::scenario::Scenario;
+use crate::script::{self, Command, Commands};
+use crate::timing::TimingCorrections;
+use crate::video::VideoOptions;
+use crate::wait::WaitOptions;
+use crate::{Error, VidiumError};
+
+/// Options used to capture and encode a page.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingOptions {
+	/// Destination file (a directory for image sequences), rendered from `output_template`
+	/// when not set.
+	pub output: Option<PathBuf>,
+	/// Destination of the video when `output` is not set, `{host}.{ext}` by default.
+	pub output_template: Option<OutputTemplate>,
+	/// Add a `-1`, `-2`, ... suffix to the name instead of overwriting an existing file.
+	pub no_clobber: bool,
+	/// Width and height of the video, taken from the first captured frame when not set.
+	///
+	/// Frames of a different size are scaled to fit and letterboxed.
+	pub size: Option<(u32, u32)>,
+	/// How frames are captured from the page.
+	pub capture: CaptureOptions,
+	/// What the recording is written as, follows the extension of the output when not set.
+	pub format: Option<Format>,
+	/// How the video is encoded.
+	pub video: VideoOptions,
+	/// How `.gif` and `.webp` animations are written.
+	pub animation: AnimationOptions,
+	/// What to wait for before the recording starts.
+	pub wait: WaitOptions,
+	/// Navigate the page to this url once capturing has started, so that the page load
+	/// is recorded too.
+	///
+	/// The control script and the scenario start once the page is loaded. `wait` is checked
+	/// before navigating.
+	pub navigate_to: Option<Url>,
+	/// Stop after recording for this long.
+	pub duration: Option<Duration>,
+	/// Stop after encoding this many frames.
+	pub max_frames: Option<u64>,
+	/// Stop when the page sends no new frames for this long.
+	pub stop_after_idle: Option<Duration>,
+	/// JavaScript evaluated in the page once the recording starts.
+	///
+	/// The script can control the recording through `window.vidium`: `stop()`, `pause()`,
+	/// `resume()` and `marker(name)`.
+	pub script: Option<String>,
+	/// Interactions performed on the page once the recording starts.
+	///
+	/// The recording stops when all steps are done.
+	pub scenario: Option<Scenario>,
+	/// Produce a constant frame rate video with this many frames per second.
+	///
+	/// By default frames are encoded as the page repaints, which gives a variable frame rate.
+	pub fps: Option<u32>,
+	/// Drive the page with a virtual clock and capture exactly `fps` frames per second of it,
+	/// no matter how long capturing and encoding take. Requires `fps`.
+	///
+	/// `duration` is counted in virtual time and `stop_after_idle` is ignored. The capture
+	/// limits and `every_nth_frame` of [`CaptureOptions`] can't be used.
+	///
+	/// The page stays on the virtual clock once the recording is stopped: timers and animations
+	/// fast-forward whenever the page is idle. Open a new page to use it in real time.
+	pub deterministic: bool,
+	/// What to do with new frames when the encoder falls behind and the decode queue is full.
+	///
+	/// Deterministic recordings always block.
+	pub on_backlog: BacklogPolicy,
+	/// Capacity of the decode queue, 16 frames when not set.
+	pub max_queued_frames: Option<usize>,
+}
+
+/// A named position in the video, created with `vidium.marker(name)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Marker {
+	pub name: String,
+	pub position: Duration,
+}
+
+/// Information about a finished recording.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSummary {
+	/// Number of encoded frames.
+	pub frames: u64,
+	/// Markers created by the control script.
+	pub markers: Vec<Marker>,
+	/// Peak depth of the decode and encode queues, shows which stage fell behind.
+	pub queues: QueueDepths,
+	/// Number of frames dropped because the decode queue was full.
+	pub dropped_frames: u64,
+	/// Screencast timestamps that were missing, duplicate or went backwards.
+	pub timing: TimingCorrections,
+}
+
+/// Starts recording an already open page.
+///
+/// The browser lifecycle is left to the caller: stopping the returned handle finalizes
+/// the video file but keeps the page and the browser open.
+pub async fn record_page(
+	page: &Page,
+	options: &RecordingOptions,
+) -> Result<RecordingHandle, VidiumError> {
+	if options.deterministic && options.fps.unwrap_or(0) == 0 {
+		return Err(VidiumError::InvalidArgument(
+			"deterministic recording requires a frame rate".into(),
+		));
+	}
+
+	if options.deterministic && options.capture.is_limited() {
+		return Err(VidiumError::InvalidArgument(
+			"max_width, max_height and every_nth_frame can't be used with a deterministic recording"
+				.into(),
+		));
+	}
+
+	let output = match &options.output {
+		Some(output) => output.clone(),
+		None => default_output(page, options)
+			.await
+			.map_err(VidiumError::Navigation)?,
+	};
+
+	let format = options.format.unwrap_or_else(|| Format::for_path(&output));
+	let target = target(&output, format, options).map_err(VidiumError::InvalidArgument)?;
+
+	// a failed wait leaves no empty or reserved file behind
+	options.wait.wait(page).await.map_err(VidiumError::Wait)?;
+
+	let destination =
+		output::prepare(&output, format, options.no_clobber).map_err(VidiumError::Encode)?;
+
+	ffmpeg_next::init().map_err(|e| VidiumError::Encode(e.to_string().into()))?;
+
+	let encoder = FrameEncoder::new(
+		destination,
+		target,
+		// the frames are not scaled back up when the capture is smaller than the page
+		options.size.map(|size| options.capture.fit(size)),
+		options.fps,
+		options.max_frames,
+	)
+	.map_err(VidiumError::Encode)?;
+
+	let commands = match &options.script {
+		Some(_) => Some(Commands::attach(page).await.map_err(VidiumError::Capture)?),
+		None => None,
+	};
+
+	let source = Source::start(page, options)
+		.await
+		.map_err(VidiumError::Capture)?;
+
+	let (stop, stopped) = oneshot::channel();
+	let task = tokio::task::spawn(record(
+		page.clone(),
+		options.clone(),
+		source,
+		commands,
+		encoder,
+		stopped,
+	));
+
+	Ok(RecordingHandle {
+		stop: Some(stop),
+		task,
+		browser: None,
+		page: None,
+	})
+}
+
+fn navigate(page: &Page, url: &Url) -> JoinHandle<Result<(), Error>> {
+	tracing::info!("navigating to {}", url);
+
+	let page = page.clone();
+	let url = url.to_string();
+	tokio::task::spawn(async move {
+		page.goto(url).await?;
+		Ok(())
+	})
+}
+
+/// Starts the control script and the scenario, returns the scenario task.
+fn drive(page: &Page, options: &RecordingOptions) -> Option<JoinHandle<Result<(), Error>>> {
+	if let Some(script) = &options.script {
+		script::run(page.clone(), script.clone());
+	}
+
+	options.scenario.clone().map(|scenario| {
+		let page = page.clone();
+		tokio::task::spawn(async move { scenario.run(&page).await })
+	})
+}
+
+/// What the frames are written as.
+fn target(output: &Path, format: Format, options: &RecordingOptions) -> Result<Target, Error> {
+	match (format, options.video.codec) {
+		(Format::Video, _) => {
+			let codec = options.video.codec(output);
+			codec.check(output)?;
+			Ok(Target::Video(options.video.encoding(codec)?))
+		}
+		(_, Some(_)) => Err("a codec can only be set for video output".into()),
+		(Format::Gif, None) => Ok(Target::Gif(options.animation.clone())),
+		(Format::Webp, None) => Ok(Target::Webp(options.animation.clone())),
+		(Format::PngSeq, None) => Ok(Target::Frames),
+	}
+}
+
+/// Renders the output template with the url and the size of the page.
+async fn default_output(page: &Page, options: &RecordingOptions) -> Result<PathBuf, Error> {
+	let url = match &options.navigate_to {
+		Some(url) => url.clone(),
+		None => {
+			let url = page.url().await?.unwrap_or_default();
+			url.parse()
+				.map_err(|e| format!("invalid page url {:?}: {}", url, e))?
+		}
+	};
+
+	let template = match (&options.output_template, options.format) {
+		(Some(template), _) => template.clone(),
+		(None, Some(Format::PngSeq)) => OutputTemplate::frames(),
+		(None, _) => OutputTemplate::default(),
+	};
+
+	let ext = match options.format {
+		Some(Format::Gif) => "gif",
+		Some(Format::Webp) => "webp",
+		Some(Format::PngSeq) => "png",
+		Some(Format::Video) | None => options.video.codec.unwrap_or_default().extension(),
+	};
+
+	let size = if template.uses("width") || template.uses("height") {
+		page.evaluate("[window.innerWidth, window.innerHeight]")
+			.await?
+			.into_value()?
+	} else {
+		(0, 0)
+	};
+
+	Ok(template.render(&OutputContext {
+		url: &url,
+		size,
+		ext,
+	}))
+}
+
+/// A running recording.
+///
+/// Dropping the handle stops the recording, but only [`RecordingHandle::stop`] waits for
+/// the video file to be finalized.
+pub struct RecordingHandle {
+	stop: Option<oneshot::Sender<()>>,
+	task: JoinHandle<Result<RecordingSummary, VidiumError>>,
+	/// The browser opened by the [`Recorder`](crate::Recorder).
+	browser: Option<BrowserSession>,
+	/// The tab opened by the [`Recorder`](crate::Recorder), closed even in a browser it connected to.
+	page: Option<Page>,
+}
+
+impl RecordingHandle {
+	pub(crate) fn with_browser(mut self, browser: BrowserSession) -> Self {
+		self.browser = Some(browser);
+		self
+	}
+
+	pub(crate) fn with_page(mut self, page: Page) -> Self {
+		self.page = Some(page);
+		self
+	}
+
+	/// Stops the screencast and finalizes the video file.
+	///
+	/// Frames that were already sent by the browser are still encoded.
+	/// The browser is closed only if it was launched by the [`Recorder`](crate::Recorder),
+	/// a browser it connected to is left running. A tab the `Recorder` opened is closed,
+	/// a recorded existing tab is left open.
+	pub async fn stop(mut self) -> Result<RecordingSummary, VidiumError> {
+		self.send_stop();
+
+		let result = (&mut self.task).await;
+		self.finish(result).await
+	}
+
+	/// Waits until the recorded page goes away on its own (e.g. the browser window is closed)
+	/// or a stop condition fires, then finalizes the video file.
+	pub async fn wait(mut self) -> Result<RecordingSummary, VidiumError> {
+		let result = (&mut self.task).await;
+		self.finish(result).await
+	}
+
+	/// Like [`RecordingHandle::wait`], but stops the recording as soon as `signal` resolves.
+	///
+	/// Also returns the output of `signal` if the recording was stopped by it.
+	pub async fn wait_or<T>(
+		mut self,
+		signal: impl Future<Output = T>,
+	) -> Result<(RecordingSummary, Option<T>), VidiumError> {
+		let (signal, result) = tokio::select! {
+			result = &mut self.task => (None, result),
+			value = signal => {
+				self.send_stop();
+				(Some(value), (&mut self.task).await)
+			}
+		};
+
+		self.finish(result).await.map(|summary| (summary, signal))
+	}
+
+	fn send_stop(&mut self) {
+		if let Some(stop) = self.stop.take() {
+			let _ = stop.send(());
+		}
+	}
+
+	async fn finish(
+		self,
+		result: Result<Result<RecordingSummary, VidiumError>, JoinError>,
+	) -> Result<RecordingSummary, VidiumError> {
+		let result = result.map_err(|e| VidiumError::Capture(e.into()));
+
+		if let Some(page) = self.page {
+			let _ = page.close().await;
+		}
+
+		if let Some(browser) = self.browser {
+			let closed = browser.close().await;
+
+			let summary = result??;
+			closed?;
+			return Ok(summary);
+		}
+
+		result?
+	}
+}
+
+async fn record(
+	page: Page,
+	options: RecordingOptions,
+	mut source: Source,
+	mut commands: Option<Commands>,
+	encoder: FrameEncoder,
+	mut stopped: oneshot::Receiver<()>,
+) -> Result<RecordingSummary, VidiumError> {
+	let mut failure = None;
+
+	// a deterministic recording counts its duration in virtual time and always has new frames
+	let deterministic = source.is_deterministic();
+
+	// a dropped frame would shift the rest of a deterministic video
+	let policy = match options.on_backlog {
+		BacklogPolicy::Block => BacklogPolicy::Block,
+		_ if deterministic => {
+			tracing::warn!(
+				"frames can't be dropped from a deterministic recording, blocking instead"
+			);
+			BacklogPolicy::Block
+		}
+		policy => policy,
+	};
+
+	let pipeline = Pipeline::start(
+		encoder,
+		options.capture.format,
+		policy,
+		options.max_queued_frames.unwrap_or(QUEUE_CAPACITY),
+	)
+	.map_err(VidiumError::Encode)?;
+
+	// with a navigation, the page is driven once it is loaded
+	let mut navigation = options.navigate_to.as_ref().map(|url| navigate(&page, url));
+	let mut scenario = match navigation {
+		Some(_) => None,
+		None => drive(&page, &options),
+	};
+
+	let deadline = options
+		.duration
+		.filter(|_| !deterministic)
+		.map(|duration| Instant::now() + duration);
+	let stop_after_idle = options.stop_after_idle.filter(|_| !deterministic);
+	let mut last_frame = Instant::now();
+
+	let drain = loop {
+		let idle = stop_after_idle.map(|idle| last_frame + idle);
+
+		let frame = tokio::select! {
+			frame = source.next() => match frame {
+				Some(Ok(frame)) => frame,
+				Some(Err(e)) => {
+					// keep what was recorded so far
+					failure = Some(VidiumError::Capture(e));
+					break false;
+				}
+				None => break false,
+			},
+			_ = &mut stopped => break true,
+			_ = sleep_until(deadline) => {
+				tracing::info!("recording duration reached");
+				break true;
+			}
+			_ = sleep_until(idle) => {
+				tracing::info!("no new frames, stopping the recording");
+				break true;
+			}
+			_ = pipeline.full() => {
+				tracing::info!("maximum number of frames reached");
+				break false;
+			}
+			Some(command) = next_command(&mut commands) => {
+				// commands go through the pipeline to apply to the frames received before them
+				let input = match command {
+					Command::Stop => {
+						tracing::info!("recording stopped by the control script");
+						break true;
+					}
+					Command::Pause => Input::Pause(Instant::now().into_std()),
+					Command::Resume => Input::Resume(Instant::now().into_std()),
+					Command::Marker(name) => Input::Marker(name, Instant::now().into_std()),
+				};
+
+				if !pipeline.push(input).await {
+					break false;
+				}
+
+				continue;
+			}
+			result = wait_task(&mut navigation) => {
+				navigation = None;
+
+				match result {
+					Ok(()) => {
+						tracing::info!("page loaded");
+						scenario = drive(&page, &options);
+						continue;
+					}
+					Err(e) => {
+						tracing::error!("navigation failed: {}", e);
+						failure = Some(VidiumError::Navigation(e));
+						break true;
+					}
+				}
+			}
+			result = wait_task(&mut scenario) => {
+				scenario = None;
+
+				match result {
+					Ok(()) => tracing::info!("scenario finished, stopping the recording"),
+					Err(e) => {
+						tracing::error!("{}", e);
+						failure = Some(VidiumError::Scenario(e));
+					}
+				}
+
+				break true;
+			}
+		};
+
+		last_frame = Instant::now();
+
+		// acknowledge first, the browser doesn't send the next frame until then
+		if let Err(e) = source.ack(&frame).await {
+			failure = Some(VidiumError::Capture(e));
+			break false;
+		}
+
+		if !pipeline
+			.push(Input::Frame(frame, last_frame.into_std()))
+			.await
+		{
+			// the encoder has failed, the error is returned by `finish`
+			break false;
+		}
+	};
+
+	for task in navigation.into_iter().chain(scenario) {
+		task.abort();
+	}
+
+	for frame in source.stop(drain).await {
+		if !pipeline
+			.push(Input::Frame(frame, std::time::Instant::now()))
+			.await
+		{
+			break;
+		}
+	}
+
+	let encoded = pipeline.finish().await?;
+
+	if let Some(e) = failure {
+		return Err(e);
+	}
+
+	Ok(RecordingSummary {
+		frames: encoded.frames,
+		markers: encoded.markers,
+		queues: encoded.queues,
+		dropped_frames: encoded.dropped,
+		timing: encoded.timing,
+	})
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+	match deadline {
+		Some(deadline) => tokio::time::sleep_until(deadline).await,
+		None => futures::future::pending().await,
+	}
+}
+
+async fn next_command(commands: &mut Option<Commands>) -> Option<Command> {
+	match commands {
+		Some(commands) => commands.next().await,
+		None => futures::future::pending().await,
+	}
+}
+
+async fn wait_task(task: &mut Option<JoinHandle<Result<(), Error>>>) -> Result<(), Error> {
+	match task {
+		Some(task) => task.await?,
+		None => futures::future::pending().await,
+	}
+}
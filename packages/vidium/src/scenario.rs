@@ -0,0 +1,252 @@
+//! Declarative scenarios: a list of interactions performed on the page while it is recorded.
+//!
+//! ```yaml
+//! steps:
+//!   - wait_for: "#login"
+//!   - click: "#login"
+//!   - type: { selector: "#email", text: "demo@example.com" }
+//!   - press: Enter
+//!   - sleep: 1000
+//!   - scroll: { y: 600 }
+//!   - hover: ".menu"
+//!   - goto: https://example.com/dashboard
+//! ```
+//!
+//! Scenarios can be written in YAML or JSON.
+
+use std::path::Path;
+use std::time::Duration;
+
+use chromiumoxide::{Element, Page};
+use serde::Deserialize;
+
+use crate::Error;
+
+/// How long `wait_for` waits for an element by default.
+const WAIT_FOR_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often `wait_for` looks for the element.
+const WAIT_FOR_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Scenario {
+	// `click: "#login"` instead of the `!click "#login"` tags serde_yaml expects for enums
+	#[serde(with = "serde_yaml::with::singleton_map_recursive")]
+	pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+	/// Navigate to the url and wait for the navigation to finish.
+	Goto(String),
+	/// Click the element matching the selector.
+	Click(String),
+	/// Type text, into the element matching the selector if it is set.
+	Type(Type),
+	/// Scroll to the element matching the selector, or by the given amount of pixels.
+	Scroll(Scroll),
+	/// Move the mouse over the element matching the selector.
+	Hover(String),
+	/// Wait until an element matching the selector appears.
+	WaitFor(String),
+	/// Wait for the given amount of milliseconds.
+	Sleep(u64),
+	/// Press a key, e.g. `Enter` or `ArrowDown`.
+	Press(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Type {
+	#[serde(default)]
+	pub selector: Option<String>,
+	pub text: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Scroll {
+	#[serde(default)]
+	pub selector: Option<String>,
+	#[serde(default)]
+	pub x: f64,
+	#[serde(default)]
+	pub y: f64,
+}
+
+impl Scenario {
+	/// Parses a YAML or JSON scenario.
+	pub fn parse(source: &str) -> Result<Self, Error> {
+		// JSON is a subset of YAML, so one parser covers both formats
+		Ok(serde_yaml::from_str(source)?)
+	}
+
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+		Self::parse(&std::fs::read_to_string(path)?)
+	}
+
+	/// Performs all steps on the page, one after another.
+	pub async fn run(&self, page: &Page) -> Result<(), Error> {
+		for (index, step) in self.steps.iter().enumerate() {
+			tracing::info!("scenario step {}: {:?}", index + 1, step);
+
+			step.run(page)
+				.await
+				.map_err(|e| format!("scenario step {} ({:?}) failed: {}", index + 1, step, e))?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Step {
+	async fn run(&self, page: &Page) -> Result<(), Error> {
+		match self {
+			Step::Goto(url) => {
+				page.goto(url.as_str()).await?;
+			}
+			Step::Click(selector) => {
+				page.find_element(selector.as_str()).await?.click().await?;
+			}
+			Step::Type(Type { selector, text }) => {
+				let element = match selector {
+					Some(selector) => {
+						let element = page.find_element(selector.as_str()).await?;
+						element.click().await?;
+						element
+					}
+					None => focused(page).await?,
+				};
+
+				element.type_str(text).await?;
+			}
+			Step::Scroll(Scroll { selector, x, y }) => match selector {
+				Some(selector) => {
+					page.find_element(selector.as_str())
+						.await?
+						.scroll_into_view()
+						.await?;
+				}
+				None => {
+					page.evaluate(format!(
+						"window.scrollBy({{ left: {}, top: {}, behavior: 'smooth' }})",
+						x, y
+					))
+					.await?;
+				}
+			},
+			Step::Hover(selector) => {
+				page.find_element(selector.as_str()).await?.hover().await?;
+			}
+			Step::WaitFor(selector) => {
+				wait_for_element(page, selector, WAIT_FOR_TIMEOUT).await?;
+			}
+			Step::Sleep(millis) => {
+				tokio::time::sleep(Duration::from_millis(*millis)).await;
+			}
+			Step::Press(key) => {
+				focused(page).await?.press_key(key).await?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Key events are dispatched to the focused element by the browser, this only gives
+/// an element to dispatch them through.
+async fn focused(page: &Page) -> Result<Element, Error> {
+	Ok(page.find_element("html").await?)
+}
+
+/// Polls the page until an element matching `selector` appears.
+pub(crate) async fn wait_for_element(
+	page: &Page,
+	selector: &str,
+	timeout: Duration,
+) -> Result<Element, Error> {
+	let deadline = tokio::time::Instant::now() + timeout;
+
+	loop {
+		match page.find_element(selector).await {
+			Ok(element) => return Ok(element),
+			Err(e) if tokio::time::Instant::now() >= deadline => {
+				return Err(format!(
+					"timed out waiting for `{}` after {:?}: {}",
+					selector, timeout, e
+				)
+				.into());
+			}
+			Err(_) => tokio::time::sleep(WAIT_FOR_INTERVAL).await,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_yaml() {
+		let scenario = Scenario::parse(
+			r##"
+steps:
+  - wait_for: "#login"
+  - click: "#login"
+  - type: { selector: "#email", text: "demo@example.com" }
+  - press: Enter
+  - sleep: 1000 # milliseconds
+  - scroll: { y: 600 }
+  - hover: ".menu"
+  - goto: https://example.com/dashboard
+"##,
+		)
+		.unwrap();
+
+		assert_eq!(scenario.steps.len(), 8);
+		assert!(matches!(&scenario.steps[0], Step::WaitFor(selector) if selector == "#login"));
+		assert!(matches!(&scenario.steps[1], Step::Click(selector) if selector == "#login"));
+		assert!(matches!(
+			&scenario.steps[2],
+			Step::Type(Type { selector: Some(selector), text })
+				if selector == "#email" && text == "demo@example.com"
+		));
+		assert!(matches!(&scenario.steps[3], Step::Press(key) if key == "Enter"));
+		assert!(matches!(scenario.steps[4], Step::Sleep(1000)));
+		assert!(matches!(
+			scenario.steps[5],
+			Step::Scroll(Scroll { selector: None, x, y }) if x == 0.0 && y == 600.0
+		));
+		assert!(matches!(&scenario.steps[6], Step::Hover(selector) if selector == ".menu"));
+		assert!(
+			matches!(&scenario.steps[7], Step::Goto(url) if url == "https://example.com/dashboard")
+		);
+	}
+
+	#[test]
+	fn parses_json() {
+		let scenario = Scenario::parse(
+			r##"{
+				"steps": [
+					{ "wait_for": "#login" },
+					{ "click": "#login" },
+					{ "type": { "selector": "#email", "text": "demo@example.com" } },
+					{ "press": "Enter" },
+					{ "sleep": 1000 },
+					{ "scroll": { "y": 600 } },
+					{ "hover": ".menu" },
+					{ "goto": "https://example.com/dashboard" }
+				]
+			}"##,
+		)
+		.unwrap();
+
+		assert_eq!(scenario.steps.len(), 8);
+		assert!(matches!(&scenario.steps[1], Step::Click(selector) if selector == "#login"));
+		assert!(matches!(scenario.steps[4], Step::Sleep(1000)));
+	}
+
+	#[test]
+	fn rejects_unknown_steps() {
+		assert!(Scenario::parse("steps:\n  - tap: \"#login\"\n").is_err());
+	}
+}
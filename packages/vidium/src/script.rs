@@ -0,0 +1,108 @@
+//! Control of the recording from inside the page.
+//!
+//! A `window.vidium` object is injected into every document of the recorded page. Calls to
+//! its methods are forwarded to vidium through the `Runtime.addBinding` CDP binding:
+//!
+//! * `vidium.stop()` — stop the recording and finalize the video file
+//! * `vidium.pause()` / `vidium.resume()` — exclude a part of the page activity from the video
+//! * `vidium.marker(name)` — remember the current position in the video under `name`
+
+use chromiumoxide::cdp::browser_protocol::page::AddScriptToEvaluateOnNewDocumentParams;
+use chromiumoxide::cdp::js_protocol::runtime::{
+	AddBindingParams, EvaluateParams, EventBindingCalled,
+};
+use chromiumoxide::listeners::EventStream;
+use chromiumoxide::Page;
+use futures::StreamExt;
+
+use crate::Error;
+
+/// Name of the binding that receives the commands.
+const BINDING: &str = "__vidium";
+
+/// Defines `window.vidium` on top of the binding.
+const PRELUDE: &str = r#"(() => {
+	const send = (command) => window.__vidium(command);
+	window.vidium = {
+		stop: () => send("stop"),
+		pause: () => send("pause"),
+		resume: () => send("resume"),
+		marker: (name) => send("marker:" + String(name)),
+	};
+})();"#;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Command {
+	Stop,
+	Pause,
+	Resume,
+	Marker(String),
+}
+
+impl Command {
+	fn parse(payload: &str) -> Option<Command> {
+		match payload {
+			"stop" => Some(Command::Stop),
+			"pause" => Some(Command::Pause),
+			"resume" => Some(Command::Resume),
+			_ => payload
+				.strip_prefix("marker:")
+				.map(|name| Command::Marker(name.to_owned())),
+		}
+	}
+}
+
+/// Commands sent by the page through `window.vidium`.
+pub(crate) struct Commands {
+	events: EventStream<EventBindingCalled>,
+}
+
+impl Commands {
+	/// Exposes `window.vidium` to the current and all future documents of the page.
+	pub(crate) async fn attach(page: &Page) -> Result<Self, Error> {
+		let events = page.event_listener::<EventBindingCalled>().await?;
+
+		page.execute(AddBindingParams::new(BINDING)).await?;
+		page.execute(AddScriptToEvaluateOnNewDocumentParams::new(PRELUDE))
+			.await?;
+		page.execute(EvaluateParams::new(PRELUDE)).await?;
+
+		Ok(Commands { events })
+	}
+
+	pub(crate) async fn next(&mut self) -> Option<Command> {
+		while let Some(event) = self.events.next().await {
+			if event.name != BINDING {
+				continue;
+			}
+
+			match Command::parse(&event.payload) {
+				Some(command) => return Some(command),
+				None => tracing::warn!("unknown vidium command: {}", event.payload),
+			}
+		}
+
+		None
+	}
+}
+
+/// Runs the control script in the page without waiting for it to complete.
+///
+/// The script is wrapped into an async function, so it can use `await` at the top level.
+pub(crate) fn run(page: Page, source: String) {
+	let expression = format!(
+		"(async () => {{\n{}\n}})().catch((e) => console.error('vidium: control script failed', e));",
+		source
+	);
+
+	tokio::task::spawn(async move {
+		match page.execute(EvaluateParams::new(expression)).await {
+			Ok(response) => {
+				if let Some(exception) = &response.exception_details {
+					tracing::warn!("control script failed: {}", exception.text);
+				}
+			}
+			Err(e) => tracing::warn!("failed to run the control script: {}", e),
+		}
+	});
+}
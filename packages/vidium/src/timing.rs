@@ -0,0 +1,239 @@
+//! Turns screencast timestamps into a monotonic timeline.
+//!
+//! Chrome usually sends increasing timestamps, but frames can come without one, with the
+//! same one as the previous frame, or even with an earlier one. None of these may move the
+//! video backwards, and none of them may drop the newer frame.
+
+use std::time::{Duration, Instant};
+
+/// Smallest distance between two frames on the timeline, a tick of the variable frame rate time base.
+const MIN_STEP: Duration = Duration::from_millis(1);
+
+/// How many timestamps had to be corrected during a recording.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimingCorrections {
+	/// Frames without a usable timestamp, timed by when they were received.
+	pub missing: u64,
+	/// Frames with the same timestamp as the previous one, placed just after it.
+	pub duplicate: u64,
+	/// Frames with a timestamp earlier than the previous one, placed just after it.
+	/// The following timestamps continue from there.
+	pub backwards: u64,
+}
+
+impl TimingCorrections {
+	pub fn total(&self) -> u64 {
+		self.missing + self.duplicate + self.backwards
+	}
+}
+
+/// Positions frames on a timeline that starts at zero with the first frame.
+#[derive(Debug, Default)]
+pub(crate) struct Timing {
+	/// Screencast time minus the timeline position, in seconds.
+	///
+	/// Taken from the first frame with a timestamp, relative to when it was received when
+	/// earlier frames had none, and moved when the timestamps go backwards.
+	offset: Option<f64>,
+	prev: Option<Previous>,
+	corrections: TimingCorrections,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Previous {
+	/// Position given by the timestamp of the frame, in seconds.
+	timestamp: f64,
+	/// Position of the frame on the timeline, `timestamp` moved after the frame before it.
+	position: Duration,
+	received_at: Instant,
+}
+
+impl Timing {
+	/// `timestamp` is in seconds, as sent in `Page.screencastFrame`.
+	///
+	/// Returns the position of the frame, later than the positions of all previous frames.
+	pub(crate) fn next(&mut self, timestamp: Option<f64>, received_at: Instant) -> Duration {
+		// where the frame would be if the timeline followed the time frames are received
+		let estimate = self.prev.map_or(Duration::ZERO, |prev| {
+			prev.position + received_at.saturating_duration_since(prev.received_at)
+		});
+
+		let timestamp = match timestamp.filter(|ts| ts.is_finite()) {
+			Some(ts) => ts - *self.offset.get_or_insert(ts - estimate.as_secs_f64()),
+			None => {
+				self.corrections.missing += 1;
+				tracing::debug!("frame without a timestamp, using the time it was received");
+				estimate.as_secs_f64()
+			}
+		};
+
+		let Some(prev) = self.prev else {
+			let position = Duration::try_from_secs_f64(timestamp).unwrap_or_default();
+			return self.advance(timestamp, position, received_at);
+		};
+
+		if timestamp == prev.timestamp {
+			self.corrections.duplicate += 1;
+			tracing::debug!("frame with a duplicate timestamp at {:?}", prev.position);
+		} else if timestamp < prev.timestamp {
+			self.corrections.backwards += 1;
+			tracing::debug!(
+				"frame timestamp went back by {:.3}s at {:?}",
+				prev.timestamp - timestamp,
+				prev.position
+			);
+		}
+
+		let position = Duration::try_from_secs_f64(timestamp)
+			.unwrap_or_default()
+			.max(prev.position + MIN_STEP);
+
+		if timestamp < prev.timestamp {
+			// the clock stepped back, later timestamps continue after this frame
+			let rebase = position.as_secs_f64() - timestamp;
+			if let Some(offset) = &mut self.offset {
+				*offset -= rebase;
+			}
+
+			return self.advance(position.as_secs_f64(), position, received_at);
+		}
+
+		self.advance(timestamp, position, received_at)
+	}
+
+	fn advance(&mut self, timestamp: f64, position: Duration, received_at: Instant) -> Duration {
+		self.prev = Some(Previous {
+			timestamp,
+			position,
+			received_at,
+		});
+
+		position
+	}
+
+	pub(crate) fn corrections(&self) -> TimingCorrections {
+		self.corrections
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Screencast timestamps are seconds since the epoch.
+	const EPOCH: f64 = 1_684_000_000.0;
+
+	fn secs(secs: f64) -> Duration {
+		Duration::from_secs_f64(secs)
+	}
+
+	fn assert_near(actual: Duration, expected: Duration) {
+		let diff = actual.as_secs_f64() - expected.as_secs_f64();
+		assert!(diff.abs() < 1e-6, "{:?} != {:?}", actual, expected);
+	}
+
+	#[test]
+	fn starts_at_zero() {
+		let mut timing = Timing::default();
+		let start = Instant::now();
+
+		assert_eq!(timing.next(Some(EPOCH), start), Duration::ZERO);
+		assert_near(timing.next(Some(EPOCH + 0.5), start), secs(0.5));
+		assert_eq!(timing.corrections(), TimingCorrections::default());
+	}
+
+	#[test]
+	fn missing_first_timestamp() {
+		let mut timing = Timing::default();
+		let start = Instant::now();
+
+		assert_eq!(timing.next(None, start), Duration::ZERO);
+		// the first timestamp continues from when it was received, not from the epoch
+		assert_near(timing.next(Some(EPOCH), start + secs(0.2)), secs(0.2));
+		assert_near(timing.next(Some(EPOCH + 1.0), start + secs(5.0)), secs(1.2));
+
+		assert_eq!(timing.corrections().missing, 1);
+	}
+
+	#[test]
+	fn missing_timestamp() {
+		let mut timing = Timing::default();
+		let start = Instant::now();
+
+		timing.next(Some(EPOCH), start);
+		assert_near(timing.next(None, start + secs(0.3)), secs(0.3));
+		assert_near(timing.next(Some(EPOCH + 0.5), start + secs(0.5)), secs(0.5));
+
+		assert_eq!(timing.corrections().missing, 1);
+	}
+
+	#[test]
+	fn missing_timestamp_received_at_once() {
+		let mut timing = Timing::default();
+		let start = Instant::now();
+
+		timing.next(Some(EPOCH), start);
+		assert_eq!(timing.next(None, start), MIN_STEP);
+	}
+
+	#[test]
+	fn duplicate_timestamp() {
+		let mut timing = Timing::default();
+		let start = Instant::now();
+
+		timing.next(Some(EPOCH), start);
+		assert_near(timing.next(Some(EPOCH + 0.1), start), secs(0.1));
+		assert_near(timing.next(Some(EPOCH + 0.1), start), secs(0.1) + MIN_STEP);
+		assert_near(timing.next(Some(EPOCH + 0.2), start), secs(0.2));
+
+		assert_eq!(timing.corrections().duplicate, 1);
+		assert_eq!(timing.corrections().total(), 1);
+	}
+
+	#[test]
+	fn backwards_timestamp() {
+		let mut timing = Timing::default();
+		let start = Instant::now();
+
+		timing.next(Some(EPOCH), start);
+		assert_near(timing.next(Some(EPOCH + 20.0), start), secs(20.0));
+
+		// the clock steps back by 10s, the frames go on from where the video is
+		assert_near(
+			timing.next(Some(EPOCH + 10.0), start),
+			secs(20.0) + MIN_STEP,
+		);
+		assert_near(
+			timing.next(Some(EPOCH + 10.5), start),
+			secs(20.5) + MIN_STEP,
+		);
+		assert_near(timing.next(None, start + secs(1.0)), secs(21.5) + MIN_STEP);
+
+		assert_eq!(timing.corrections().backwards, 1);
+		assert_eq!(timing.corrections().missing, 1);
+	}
+
+	#[test]
+	fn always_increasing() {
+		let mut timing = Timing::default();
+		let start = Instant::now();
+
+		let timestamps = [
+			None,
+			Some(EPOCH + 5.0),
+			Some(EPOCH + 5.0),
+			Some(EPOCH + 4.0),
+			Some(f64::NAN),
+			None,
+			Some(EPOCH + 4.0),
+			Some(EPOCH + 6.0),
+		];
+
+		let mut prev = None;
+		for ts in timestamps {
+			let position = timing.next(ts, start);
+			assert!(prev < Some(position), "{:?} after {:?}", position, prev);
+			prev = Some(position);
+		}
+	}
+}
@@ -0,0 +1,643 @@
+//! Video files written with ffmpeg.
+
+use std::path::Path;
+use std::time::Duration;
+
+use ffmpeg_next::format::{self, Pixel};
+use ffmpeg_next::software::scaling;
+use ffmpeg_next::{codec, encoder, frame, Dictionary, Packet, Rational};
+use image::RgbImage;
+
+use crate::Error;
+
+/// Time base of variable frame rate videos, frames are placed with millisecond precision.
+const VFR_TIME_BASE: i32 = 1000;
+
+/// Video codec, the container follows the extension of the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Codec {
+	/// Plays everywhere, `.mp4` by default.
+	#[default]
+	H264,
+	/// Smaller than h264 at the same quality, `.webm` by default.
+	Vp9,
+	/// The smallest, but the slowest to encode, `.webm` by default.
+	Av1,
+	/// Lossless and intra-only, for archival and pixel-exact comparison, `.mkv` only.
+	Ffv1,
+}
+
+/// Pixel format of the video.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PixelFormat {
+	/// Color at a quarter of the resolution, plays everywhere.
+	#[default]
+	Yuv420p,
+	/// Full resolution color, keeps colored text and thin lines sharp.
+	Yuv444p,
+	/// The RGB frames as captured, planar RGB for codecs other than h264.
+	Rgb24,
+}
+
+impl Codec {
+	/// Codec for an output without `--codec`: vp9 for `.webm`, h264 otherwise.
+	pub(crate) fn for_path(path: &Path) -> Codec {
+		match extension(path).as_deref() {
+			Some("webm") => Codec::Vp9,
+			_ => Codec::H264,
+		}
+	}
+
+	/// Extension of the default container.
+	pub(crate) fn extension(self) -> &'static str {
+		match self {
+			Codec::H264 => "mp4",
+			Codec::Vp9 | Codec::Av1 => "webm",
+			Codec::Ffv1 => "mkv",
+		}
+	}
+
+	/// Fails for containers that can't hold the codec.
+	pub(crate) fn check(self, path: &Path) -> Result<(), Error> {
+		match (self, extension(path).as_deref()) {
+			(Codec::H264, Some("webm")) => {
+				Err("webm files can't contain h264, use vp9 or av1".into())
+			}
+			(Codec::Ffv1, ext) if ext != Some("mkv") => Err("ffv1 needs a .mkv output".into()),
+			_ => Ok(()),
+		}
+	}
+
+	/// ffmpeg encoders in the order of preference, with defaults tuned for screen content.
+	pub(crate) fn encoding(self) -> Encoding {
+		let encoders: &[&str] = match self {
+			Codec::H264 => &["libx264"],
+			Codec::Vp9 => &["libvpx-vp9"],
+			Codec::Av1 => &["libsvtav1", "libaom-av1"],
+			Codec::Ffv1 => &["ffv1"],
+		};
+
+		Encoding {
+			encoders: encoders
+				.iter()
+				.map(|name| (*name, Codec::options(name)))
+				.collect(),
+			pixel_format: Pixel::YUV420P,
+			// every ffv1 frame is a keyframe
+			gop: (self == Codec::Ffv1).then_some(1),
+			muxer_options: Vec::new(),
+		}
+	}
+
+	/// Defaults for screen content: large flat areas, sharp text, little motion.
+	fn options(encoder: &str) -> Vec<(String, String)> {
+		let defaults: &[(&str, &str)] = match encoder {
+			"libx264" | "libx264rgb" => &[("preset", "veryfast"), ("crf", "23")],
+			// constant quality, row based multithreading and the screen content tools
+			"libvpx-vp9" => &[
+				("crf", "32"),
+				("b", "0"),
+				("deadline", "realtime"),
+				("cpu-used", "8"),
+				("row-mt", "1"),
+				("tune-content", "screen"),
+			],
+			"libsvtav1" => &[("preset", "10"), ("crf", "35"), ("svtav1-params", "scm=1")],
+			"libaom-av1" => &[
+				("crf", "35"),
+				("b", "0"),
+				("usage", "realtime"),
+				("cpu-used", "8"),
+				("row-mt", "1"),
+			],
+			// the latest version, with checksums to detect damaged archives
+			"ffv1" => &[("level", "3"), ("slicecrc", "1")],
+			_ => &[],
+		};
+
+		defaults
+			.iter()
+			.map(|(key, value)| (key.to_string(), value.to_string()))
+			.collect()
+	}
+}
+
+/// An ffmpeg encoder and its settings.
+#[derive(Debug, Clone)]
+pub(crate) struct Encoding {
+	/// Encoders with their options, the first one ffmpeg is built with is used.
+	pub encoders: Vec<(&'static str, Vec<(String, String)>)>,
+	pub pixel_format: Pixel,
+	/// Frames between keyframes, 2 seconds (60 frames without a frame rate) when not set.
+	pub gop: Option<u32>,
+	/// Options of the container.
+	pub muxer_options: Vec<(String, String)>,
+}
+
+/// What the encoder is tuned for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Tune {
+	/// Mostly static pages: dashboards, documents, slides.
+	Stillimage,
+	/// Fast motion: transitions, scrolling, canvas animations.
+	Animation,
+}
+
+/// How the video is encoded.
+///
+/// Options that are not set keep the defaults of the codec, see [`Codec`].
+#[derive(Debug, Clone, Default)]
+pub struct VideoOptions {
+	/// Taken from the extension of the output when not set: vp9 for `.webm`, ffv1 for lossless
+	/// `.mkv`, h264 otherwise.
+	pub codec: Option<Codec>,
+	/// Constant quality, lower is better: 0-51 for h264, 0-63 for vp9 and av1.
+	pub crf: Option<u8>,
+	/// Speed of the encoder: a preset name for h264 (`ultrafast` to `veryslow`),
+	/// `cpu-used` for vp9 and libaom (0-8), a preset number for SVT-AV1 (0-13).
+	pub preset: Option<String>,
+	/// Target bitrate in bits per second, replaces the constant quality unless `crf` is set too.
+	pub bitrate: Option<u64>,
+	/// Upper bound of the bitrate in bits per second.
+	pub max_bitrate: Option<u64>,
+	/// Frames between keyframes, 2 seconds by default.
+	pub gop: Option<u32>,
+	/// h264 `tune`, and the content tuning of vp9.
+	pub tune: Option<Tune>,
+	/// yuv420p by default, which rounds the size of the video down to even dimensions.
+	pub pixel_format: PixelFormat,
+	/// Encode without any loss: qp 0 for h264, lossless mode for vp9, FFV1 for `.mkv` outputs.
+	///
+	/// Combine with [`PixelFormat::Yuv444p`] or [`PixelFormat::Rgb24`] for pixel-exact videos,
+	/// a warning is logged for [`PixelFormat::Yuv420p`].
+	pub lossless: bool,
+}
+
+impl VideoOptions {
+	/// Codec used for `path`.
+	pub(crate) fn codec(&self, path: &Path) -> Codec {
+		match (self.codec, extension(path).as_deref()) {
+			(Some(codec), _) => codec,
+			(None, Some("mkv")) if self.lossless => Codec::Ffv1,
+			(None, _) => Codec::for_path(path),
+		}
+	}
+
+	/// Defaults of `codec` with these options applied.
+	pub(crate) fn encoding(&self, codec: Codec) -> Result<Encoding, Error> {
+		let mut encoding = codec.encoding();
+		encoding.gop = self.gop.or(encoding.gop);
+
+		if self.lossless && codec == Codec::Av1 {
+			return Err("av1 has no lossless mode, use h264, vp9 or ffv1".into());
+		}
+
+		if self.lossless && self.pixel_format == PixelFormat::Yuv420p {
+			// the encoder is lossless, but the color is subsampled before it gets there
+			tracing::warn!(
+				"lossless yuv420p video keeps color at half the resolution, \
+				use yuv444p or rgb24 for pixel-exact frames"
+			);
+		}
+
+		let max_crf = match codec {
+			Codec::H264 => Some(("h264", 51)),
+			Codec::Vp9 => Some(("vp9", 63)),
+			Codec::Av1 => Some(("av1", 63)),
+			Codec::Ffv1 => None,
+		};
+
+		if let (Some(crf), Some((name, max))) = (self.crf, max_crf) {
+			if crf > max {
+				return Err(
+					format!("the crf of {} goes from 0 to {}, got {}", name, max, crf).into(),
+				);
+			}
+		}
+
+		encoding.pixel_format = match (self.pixel_format, codec) {
+			(PixelFormat::Yuv420p, _) => Pixel::YUV420P,
+			(PixelFormat::Yuv444p, _) => Pixel::YUV444P,
+			(PixelFormat::Rgb24, Codec::H264) => Pixel::RGB24,
+			(PixelFormat::Rgb24, _) => Pixel::GBRP,
+		};
+
+		if self.pixel_format != PixelFormat::Yuv420p {
+			// SVT-AV1 only supports 4:2:0
+			encoding.encoders.retain(|(name, _)| *name != "libsvtav1");
+		}
+
+		if self.pixel_format == PixelFormat::Rgb24 {
+			for (name, _) in &mut encoding.encoders {
+				if *name == "libx264" {
+					*name = "libx264rgb";
+				}
+			}
+		}
+
+		for (name, options) in &mut encoding.encoders {
+			if let Some(bitrate) = self.bitrate {
+				set_option(options, "b", bitrate.to_string());
+			}
+
+			if let Some(max_bitrate) = self.max_bitrate {
+				set_option(options, "maxrate", max_bitrate.to_string());
+				// the rate control buffer holds 2 seconds at the maximum bitrate
+				set_option(options, "bufsize", (max_bitrate * 2).to_string());
+			}
+
+			match (self.crf, self.bitrate) {
+				(Some(crf), _) => set_option(options, "crf", crf.to_string()),
+				// a bitrate alone switches to average bitrate encoding
+				(None, Some(_)) => options.retain(|(key, _)| key != "crf"),
+				(None, None) => {}
+			}
+
+			if let Some(preset) = &self.preset {
+				let key = match *name {
+					"libvpx-vp9" | "libaom-av1" => "cpu-used",
+					_ => "preset",
+				};
+				set_option(options, key, preset.clone());
+			}
+
+			if self.lossless {
+				match *name {
+					"libx264" | "libx264rgb" => {
+						options.retain(|(key, _)| key != "crf");
+						set_option(options, "qp", "0".to_owned());
+					}
+					"libvpx-vp9" => set_option(options, "lossless", "1".to_owned()),
+					_ => {}
+				}
+			}
+
+			let tune = match (*name, self.tune) {
+				("libx264" | "libx264rgb", Some(Tune::Stillimage)) => Some(("tune", "stillimage")),
+				("libx264" | "libx264rgb", Some(Tune::Animation)) => Some(("tune", "animation")),
+				("libvpx-vp9", Some(Tune::Stillimage)) => Some(("tune-content", "screen")),
+				("libvpx-vp9", Some(Tune::Animation)) => Some(("tune-content", "default")),
+				_ => None,
+			};
+
+			if let Some((key, value)) = tune {
+				set_option(options, key, value.to_owned());
+			}
+		}
+
+		Ok(encoding)
+	}
+}
+
+/// Encodes RGB frames into a video file.
+pub(crate) struct VideoEncoder {
+	output: format::context::Output,
+	encoder: encoder::video::Encoder,
+	scaler: scaling::Context,
+	stream: usize,
+	time_base: Rational,
+	stream_time_base: Rational,
+	last_pts: Option<i64>,
+}
+
+// SAFETY: the ffmpeg contexts are owned by the encoder and only used by one thread at a time.
+unsafe impl Send for VideoEncoder {}
+
+impl VideoEncoder {
+	/// Opens `path` for a `width`x`height` video, with a constant frame rate if `fps` is set.
+	pub(crate) fn new(
+		path: &Path,
+		encoding: &Encoding,
+		width: u32,
+		height: u32,
+		fps: Option<u32>,
+	) -> Result<Self, Error> {
+		let (name, found, options) = encoding
+			.encoders
+			.iter()
+			.find_map(|(name, options)| {
+				encoder::find_by_name(name).map(|found| (*name, found, options))
+			})
+			.ok_or_else(|| {
+				let names: Vec<_> = encoding.encoders.iter().map(|(name, _)| *name).collect();
+				format!("ffmpeg is built without {}", names.join(" or "))
+			})?;
+
+		tracing::info!("encoding {}x{} video with {}", width, height, name);
+
+		let mut output = format::output(path)?;
+		let global_header = output
+			.format()
+			.flags()
+			.contains(format::flag::Flags::GLOBAL_HEADER);
+
+		let time_base = Rational::new(1, fps.map_or(VFR_TIME_BASE, |fps| fps as i32));
+
+		let mut context = codec::context::Context::new().encoder().video()?;
+
+		context.set_width(width);
+		context.set_height(height);
+		context.set_format(encoding.pixel_format);
+		context.set_time_base(time_base);
+		context.set_frame_rate(fps.map(|fps| Rational::new(fps as i32, 1)));
+		// a keyframe every couple of seconds keeps the video seekable
+		context.set_gop(encoding.gop.unwrap_or(fps.unwrap_or(30) * 2));
+
+		if global_header {
+			context.set_flags(codec::Flags::GLOBAL_HEADER);
+		}
+
+		let encoder = context.open_as_with(found, dictionary(options))?;
+
+		let stream = {
+			let mut stream = output.add_stream(found)?;
+			stream.set_time_base(time_base);
+			stream.set_parameters(&encoder);
+			stream.index()
+		};
+
+		if encoding.muxer_options.is_empty() {
+			output.write_header()?;
+		} else {
+			output.write_header_with(dictionary(&encoding.muxer_options))?;
+		}
+
+		let stream_time_base = output
+			.stream(stream)
+			.ok_or("the video stream is missing")?
+			.time_base();
+
+		let scaler = scaling::Context::get(
+			Pixel::RGB24,
+			width,
+			height,
+			encoding.pixel_format,
+			width,
+			height,
+			scaling::Flags::BILINEAR,
+		)?;
+
+		Ok(VideoEncoder {
+			output,
+			encoder,
+			scaler,
+			stream,
+			time_base,
+			stream_time_base,
+			last_pts: None,
+		})
+	}
+
+	/// Encodes `image` shown from `position` on.
+	pub(crate) fn encode(&mut self, image: &RgbImage, position: Duration) -> Result<(), Error> {
+		let mut rgb = frame::Video::new(Pixel::RGB24, image.width(), image.height());
+
+		let stride = rgb.stride(0);
+		let row = image.width() as usize * 3;
+		for (y, line) in image.as_raw().chunks_exact(row).enumerate() {
+			rgb.data_mut(0)[y * stride..y * stride + row].copy_from_slice(line);
+		}
+
+		let mut yuv = frame::Video::empty();
+		self.scaler.run(&rgb, &mut yuv)?;
+
+		let den = i128::from(self.time_base.denominator());
+		let pts = (position.as_nanos() as i128 * den / 1_000_000_000) as i64;
+		// encoders need strictly increasing timestamps
+		let pts = match self.last_pts {
+			Some(last) if pts <= last => last + 1,
+			_ => pts,
+		};
+
+		self.last_pts = Some(pts);
+		yuv.set_pts(Some(pts));
+
+		self.encoder.send_frame(&yuv)?;
+		self.write_packets()
+	}
+
+	/// Flushes the encoder and finalizes the file.
+	pub(crate) fn finish(&mut self) -> Result<(), Error> {
+		self.encoder.send_eof()?;
+		self.write_packets()?;
+		self.output.write_trailer()?;
+		Ok(())
+	}
+
+	fn write_packets(&mut self) -> Result<(), Error> {
+		let mut packet = Packet::empty();
+
+		while self.encoder.receive_packet(&mut packet).is_ok() {
+			packet.set_stream(self.stream);
+			packet.rescale_ts(self.time_base, self.stream_time_base);
+			packet.write_interleaved(&mut self.output)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Lowercase extension of `path`.
+pub(crate) fn extension(path: &Path) -> Option<String> {
+	path.extension()
+		.map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+}
+
+/// Sets `key`, replacing the default.
+fn set_option(options: &mut Vec<(String, String)>, key: &str, value: String) {
+	options.retain(|(k, _)| k != key);
+	options.push((key.to_owned(), value));
+}
+
+fn dictionary(options: &[(String, String)]) -> Dictionary<'static> {
+	let mut dictionary = Dictionary::new();
+	for (key, value) in options {
+		dictionary.set(key, value);
+	}
+	dictionary
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn options<'a>(encoding: &'a Encoding, encoder: &str) -> &'a [(String, String)] {
+		encoding
+			.encoders
+			.iter()
+			.find(|(name, _)| *name == encoder)
+			.map(|(_, options)| options.as_slice())
+			.unwrap()
+	}
+
+	fn option<'a>(encoding: &'a Encoding, encoder: &str, key: &str) -> Option<&'a str> {
+		options(encoding, encoder)
+			.iter()
+			.find(|(k, _)| k == key)
+			.map(|(_, value)| value.as_str())
+	}
+
+	#[test]
+	fn defaults() {
+		let encoding = VideoOptions::default().encoding(Codec::H264).unwrap();
+
+		assert_eq!(option(&encoding, "libx264", "crf"), Some("23"));
+		assert_eq!(option(&encoding, "libx264", "preset"), Some("veryfast"));
+		assert_eq!(encoding.pixel_format, Pixel::YUV420P);
+		assert_eq!(encoding.gop, None);
+
+		let encoding = VideoOptions::default().encoding(Codec::Ffv1).unwrap();
+		assert_eq!(encoding.gop, Some(1));
+	}
+
+	#[test]
+	fn bitrate_replaces_crf() {
+		let video = VideoOptions {
+			bitrate: Some(2_000_000),
+			..VideoOptions::default()
+		};
+
+		for codec in [Codec::H264, Codec::Vp9, Codec::Av1] {
+			let encoding = video.encoding(codec).unwrap();
+
+			for (name, options) in &encoding.encoders {
+				assert!(options.iter().all(|(key, _)| key != "crf"), "{}", name);
+				assert_eq!(option(&encoding, name, "b"), Some("2000000"));
+			}
+		}
+	}
+
+	#[test]
+	fn crf_and_bitrate() {
+		let video = VideoOptions {
+			crf: Some(30),
+			bitrate: Some(2_000_000),
+			..VideoOptions::default()
+		};
+
+		let encoding = video.encoding(Codec::Vp9).unwrap();
+		assert_eq!(option(&encoding, "libvpx-vp9", "crf"), Some("30"));
+		assert_eq!(option(&encoding, "libvpx-vp9", "b"), Some("2000000"));
+	}
+
+	#[test]
+	fn max_bitrate() {
+		let video = VideoOptions {
+			max_bitrate: Some(4_000_000),
+			..VideoOptions::default()
+		};
+
+		let encoding = video.encoding(Codec::H264).unwrap();
+		assert_eq!(option(&encoding, "libx264", "maxrate"), Some("4000000"));
+		assert_eq!(option(&encoding, "libx264", "bufsize"), Some("8000000"));
+		// the constant quality is kept, capped by the maximum bitrate
+		assert_eq!(option(&encoding, "libx264", "crf"), Some("23"));
+	}
+
+	#[test]
+	fn preset_per_encoder() {
+		let video = VideoOptions {
+			preset: Some("6".to_owned()),
+			..VideoOptions::default()
+		};
+
+		let encoding = video.encoding(Codec::Vp9).unwrap();
+		assert_eq!(option(&encoding, "libvpx-vp9", "cpu-used"), Some("6"));
+		assert_eq!(option(&encoding, "libvpx-vp9", "preset"), None);
+
+		let encoding = video.encoding(Codec::Av1).unwrap();
+		assert_eq!(option(&encoding, "libsvtav1", "preset"), Some("6"));
+		assert_eq!(option(&encoding, "libaom-av1", "cpu-used"), Some("6"));
+		assert_eq!(option(&encoding, "libaom-av1", "preset"), None);
+
+		let video = VideoOptions {
+			preset: Some("slow".to_owned()),
+			..VideoOptions::default()
+		};
+
+		let encoding = video.encoding(Codec::H264).unwrap();
+		assert_eq!(option(&encoding, "libx264", "preset"), Some("slow"));
+	}
+
+	#[test]
+	fn tune() {
+		let video = VideoOptions {
+			tune: Some(Tune::Animation),
+			..VideoOptions::default()
+		};
+
+		let encoding = video.encoding(Codec::Vp9).unwrap();
+		assert_eq!(
+			option(&encoding, "libvpx-vp9", "tune-content"),
+			Some("default")
+		);
+
+		let encoding = video.encoding(Codec::H264).unwrap();
+		assert_eq!(option(&encoding, "libx264", "tune"), Some("animation"));
+
+		let video = VideoOptions {
+			tune: Some(Tune::Stillimage),
+			..VideoOptions::default()
+		};
+
+		let encoding = video.encoding(Codec::Vp9).unwrap();
+		assert_eq!(
+			option(&encoding, "libvpx-vp9", "tune-content"),
+			Some("screen")
+		);
+		assert_eq!(option(&encoding, "libvpx-vp9", "tune"), None);
+	}
+
+	#[test]
+	fn lossless() {
+		let video = VideoOptions {
+			lossless: true,
+			pixel_format: PixelFormat::Yuv444p,
+			..VideoOptions::default()
+		};
+
+		let encoding = video.encoding(Codec::H264).unwrap();
+		assert_eq!(option(&encoding, "libx264", "qp"), Some("0"));
+		assert_eq!(option(&encoding, "libx264", "crf"), None);
+
+		let encoding = video.encoding(Codec::Vp9).unwrap();
+		assert_eq!(option(&encoding, "libvpx-vp9", "lossless"), Some("1"));
+
+		assert!(video.encoding(Codec::Av1).is_err());
+	}
+
+	#[test]
+	fn rgb() {
+		let video = VideoOptions {
+			pixel_format: PixelFormat::Rgb24,
+			..VideoOptions::default()
+		};
+
+		let encoding = video.encoding(Codec::H264).unwrap();
+		assert_eq!(encoding.encoders[0].0, "libx264rgb");
+		assert_eq!(encoding.pixel_format, Pixel::RGB24);
+
+		let encoding = video.encoding(Codec::Av1).unwrap();
+		assert_eq!(encoding.pixel_format, Pixel::GBRP);
+		// SVT-AV1 only encodes 4:2:0
+		assert_eq!(encoding.encoders.len(), 1);
+		assert_eq!(encoding.encoders[0].0, "libaom-av1");
+	}
+
+	#[test]
+	fn crf_range() {
+		let video = |crf| VideoOptions {
+			crf: Some(crf),
+			..VideoOptions::default()
+		};
+
+		assert!(video(51).encoding(Codec::H264).is_ok());
+		assert!(video(52).encoding(Codec::H264).is_err());
+		assert!(video(63).encoding(Codec::Vp9).is_ok());
+		assert!(video(64).encoding(Codec::Vp9).is_err());
+		assert!(video(64).encoding(Codec::Av1).is_err());
+	}
+}
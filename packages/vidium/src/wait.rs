@@ -0,0 +1,189 @@
+//! Conditions the page has to meet before the recording starts.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+
+use chromiumoxide::cdp::browser_protocol::network::{
+	EventLoadingFailed, EventLoadingFinished, EventRequestWillBeSent,
+};
+use chromiumoxide::cdp::js_protocol::runtime::{EvaluateParams, ExceptionDetails};
+use chromiumoxide::error::CdpError;
+use chromiumoxide::Page;
+use futures::StreamExt;
+use tokio::time::Instant;
+
+use crate::scenario::wait_for_element;
+use crate::Error;
+
+/// How long each condition is waited for by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the function condition is evaluated.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What to wait for after the page is opened and before the first frame is captured.
+///
+/// The conditions are checked one after another: selector, function, network idle, delay.
+#[derive(Debug, Clone, Default)]
+pub struct WaitOptions {
+	/// Wait until an element matching the css selector appears.
+	pub selector: Option<String>,
+	/// Wait until no network requests are in flight for this long.
+	pub network_idle: Option<Duration>,
+	/// Wait until the JavaScript expression is truthy, a returned promise is awaited.
+	pub function: Option<String>,
+	/// Wait for this long once the other conditions are met.
+	pub delay: Option<Duration>,
+	/// How long each condition is waited for, 30 seconds when not set.
+	pub timeout: Option<Duration>,
+}
+
+impl WaitOptions {
+	pub(crate) async fn wait(&self, page: &Page) -> Result<(), Error> {
+		let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+
+		if let Some(selector) = &self.selector {
+			tracing::info!("waiting for `{}`", selector);
+			wait_for_element(page, selector, timeout).await?;
+		}
+
+		if let Some(function) = &self.function {
+			tracing::info!("waiting for `{}` to be truthy", function);
+			let mut last_error = None;
+			with_timeout(
+				timeout,
+				format!("`{}` to be truthy", function),
+				wait_for_function(page, function, &mut last_error),
+			)
+			.await
+			.map_err(|e| match last_error {
+				Some(error) => format!("{}, the last evaluation failed: {}", e, error).into(),
+				None => e,
+			})?;
+		}
+
+		if let Some(idle) = self.network_idle {
+			tracing::info!("waiting for the network to be idle for {:?}", idle);
+			with_timeout(
+				timeout,
+				"the network to be idle".to_owned(),
+				wait_for_network_idle(page, idle),
+			)
+			.await?;
+		}
+
+		if let Some(delay) = self.delay {
+			tracing::info!("waiting for {:?} before recording", delay);
+			tokio::time::sleep(delay).await;
+		}
+
+		Ok(())
+	}
+}
+
+async fn with_timeout(
+	timeout: Duration,
+	condition: String,
+	wait: impl Future<Output = Result<(), Error>>,
+) -> Result<(), Error> {
+	match tokio::time::timeout(timeout, wait).await {
+		Ok(result) => result,
+		Err(_) => Err(format!("timed out waiting for {} after {:?}", condition, timeout).into()),
+	}
+}
+
+/// Polls the page until `expression` evaluates to a truthy value.
+///
+/// Fails right away if `expression` is not valid JavaScript, other errors are kept in
+/// `last_error` and retried.
+async fn wait_for_function(
+	page: &Page,
+	expression: &str,
+	last_error: &mut Option<String>,
+) -> Result<(), Error> {
+	let params = EvaluateParams::builder()
+		.expression(format!(
+			"Promise.resolve({}).then((value) => !!value)",
+			expression
+		))
+		.await_promise(true)
+		.return_by_value(true)
+		.build()?;
+
+	loop {
+		// the expression may fail until the page is ready, e.g. while a global is not defined yet
+		match page.evaluate(params.clone()).await {
+			Ok(result) => {
+				if result.value().and_then(|value| value.as_bool()) == Some(true) {
+					return Ok(());
+				}
+			}
+			Err(CdpError::JavascriptException(details)) => {
+				let message = exception_message(&details);
+
+				let syntax_error = details
+					.exception
+					.as_ref()
+					.and_then(|exception| exception.class_name.as_deref())
+					== Some("SyntaxError");
+
+				if syntax_error {
+					return Err(format!("`{}` is not valid: {}", expression, message).into());
+				}
+
+				tracing::debug!("`{}` failed: {}", expression, message);
+				*last_error = Some(message);
+			}
+			Err(e) => {
+				tracing::debug!("`{}` failed: {}", expression, e);
+				*last_error = Some(e.to_string());
+			}
+		}
+
+		tokio::time::sleep(POLL_INTERVAL).await;
+	}
+}
+
+/// The first line of a JavaScript exception, e.g. `ReferenceError: app is not defined`.
+fn exception_message(details: &ExceptionDetails) -> String {
+	details
+		.exception
+		.as_ref()
+		.and_then(|exception| exception.description.as_deref())
+		.and_then(|description| description.lines().next())
+		.unwrap_or(&details.text)
+		.to_owned()
+}
+
+/// Waits until no requests started by the page are in flight for `idle`.
+async fn wait_for_network_idle(page: &Page, idle: Duration) -> Result<(), Error> {
+	let mut sent = page.event_listener::<EventRequestWillBeSent>().await?;
+	let mut finished = page.event_listener::<EventLoadingFinished>().await?;
+	let mut failed = page.event_listener::<EventLoadingFailed>().await?;
+
+	let mut in_flight = HashSet::new();
+	let mut quiet_since = Instant::now();
+
+	loop {
+		tokio::select! {
+			Some(event) = sent.next() => {
+				in_flight.insert(event.request_id.clone());
+			}
+			Some(event) = finished.next() => {
+				if in_flight.remove(&event.request_id) && in_flight.is_empty() {
+					quiet_since = Instant::now();
+				}
+			}
+			Some(event) = failed.next() => {
+				if in_flight.remove(&event.request_id) && in_flight.is_empty() {
+					quiet_since = Instant::now();
+				}
+			}
+			_ = tokio::time::sleep_until(quiet_since + idle), if in_flight.is_empty() => {
+				return Ok(());
+			}
+			else => return Err("the page was closed while waiting for the network".into()),
+		}
+	}
+}